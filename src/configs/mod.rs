@@ -16,8 +16,17 @@ pub enum ConfName {
     Mp3Bitrate,
     YoutubeApiKey,
     YoutubeMaxResults,
+    YoutubeSearchMaxResults,
+    YoutubeDurationLookupParallelism,
+    YoutubeApiDailyQuota,
+    YoutubeDurationLookupTimeoutSeconds,
+    DurationProbeParallelism,
+    DurationProbeTimeoutSeconds,
+    DurationProbeMaxItems,
+    YoutubeSearchPublishedAfterDays,
     TwitchClientId,
     TwitchSecretKey,
+    TwitchMaxResults,
     TranscodingEnabled,
     SubfolderPath,
     ValidUrlDomains,
@@ -28,6 +37,9 @@ pub enum ConfName {
     FfmpegTimeoutSeconds,
     Host,
     Port,
+    AdminToken,
+    ListenLaterToken,
+    FeedGroups,
 }
 
 struct EnvConf {}
@@ -67,6 +79,9 @@ impl Conf for EnvConf {
                         Ok(s)
                     }
                 }),
+            ConfName::TwitchMaxResults => {
+                Ok(std::env::var("TWITCH_MAX_RESULTS").unwrap_or_else(|_| "100".to_string()))
+            }
             ConfName::YoutubeApiKey => std::env::var("YT_API_KEY")
                 .map_err(|e| eyre::eyre!(e))
                 .and_then(|s| {
@@ -111,6 +126,34 @@ impl Conf for EnvConf {
             ConfName::YoutubeMaxResults => {
                 Ok(std::env::var("YOUTUBE_MAX_RESULTS").unwrap_or_else(|_| "300".to_string()))
             }
+            ConfName::YoutubeSearchMaxResults => Ok(
+                std::env::var("YOUTUBE_SEARCH_MAX_RESULTS").unwrap_or_else(|_| "50".to_string())
+            ),
+            ConfName::YoutubeApiDailyQuota => Ok(
+                std::env::var("YOUTUBE_API_DAILY_QUOTA").unwrap_or_else(|_| "10000".to_string())
+            ),
+            ConfName::YoutubeDurationLookupParallelism => {
+                Ok(std::env::var("YOUTUBE_DURATION_LOOKUP_PARALLELISM")
+                    .unwrap_or_else(|_| "4".to_string()))
+            }
+            ConfName::YoutubeDurationLookupTimeoutSeconds => {
+                Ok(std::env::var("YOUTUBE_DURATION_LOOKUP_TIMEOUT_SECONDS")
+                    .unwrap_or_else(|_| "30".to_string()))
+            }
+            ConfName::DurationProbeParallelism => {
+                Ok(std::env::var("DURATION_PROBE_PARALLELISM").unwrap_or_else(|_| "4".to_string()))
+            }
+            ConfName::DurationProbeTimeoutSeconds => {
+                Ok(std::env::var("DURATION_PROBE_TIMEOUT_SECONDS")
+                    .unwrap_or_else(|_| "60".to_string()))
+            }
+            ConfName::DurationProbeMaxItems => {
+                Ok(std::env::var("DURATION_PROBE_MAX_ITEMS").unwrap_or_else(|_| "20".to_string()))
+            }
+            ConfName::YoutubeSearchPublishedAfterDays => {
+                Ok(std::env::var("YOUTUBE_SEARCH_PUBLISHED_AFTER_DAYS")
+                    .unwrap_or_else(|_| "30".to_string()))
+            }
             ConfName::YoutubeYtDlpExtraArgs => {
                 Ok(std::env::var("YOUTUBE_YT_DLP_GET_URL_EXTRA_ARGS")
                     .unwrap_or_else(|_| "[]".to_string()))
@@ -127,12 +170,34 @@ impl Conf for EnvConf {
             ConfName::Port => {
                 Ok(std::env::var("VOD2POD_RSS_PORT").unwrap_or_else(|_| "8080".to_string()))
             }
+            ConfName::AdminToken => std::env::var("ADMIN_TOKEN")
+                .map_err(|e| eyre::eyre!(e))
+                .and_then(|s| {
+                    if s.is_empty() {
+                        Err(eyre::eyre!("no admin token"))
+                    } else {
+                        Ok(s)
+                    }
+                }),
+            ConfName::ListenLaterToken => std::env::var("LISTEN_LATER_TOKEN")
+                .map_err(|e| eyre::eyre!(e))
+                .and_then(|s| {
+                    if s.is_empty() {
+                        Err(eyre::eyre!("no listen later token"))
+                    } else {
+                        Ok(s)
+                    }
+                }),
+            ConfName::FeedGroups => {
+                Ok(std::env::var("FEED_GROUPS").unwrap_or_else(|_| "{}".to_string()))
+            }
         }
     }
 }
 
-#[derive(Serialize, Clone, Copy)]
+#[derive(Serialize, Clone, Copy, Default)]
 pub enum AudioCodec {
+    #[default]
     MP3,
     Opus,
     OGGVorbis,
@@ -180,9 +245,3 @@ impl From<String> for AudioCodec {
         }
     }
 }
-
-impl Default for AudioCodec {
-    fn default() -> Self {
-        Self::MP3
-    }
-}
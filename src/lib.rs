@@ -1,6 +1,7 @@
 use configs::{conf, Conf, ConfName};
 
 pub mod configs;
+pub mod listen_later;
 pub mod provider;
 pub mod rss_transcodizer;
 pub mod server;
@@ -13,4 +14,3 @@ pub async fn get_redis_client() -> Result<redis::aio::MultiplexedConnection, eyr
     let con = client.get_multiplexed_async_connection().await?;
     Ok(con)
 }
-
@@ -0,0 +1,155 @@
+use chrono::Utc;
+use eyre::eyre;
+use log::{debug, info, warn};
+use reqwest::Url;
+use rss::extension::itunes::ITunesItemExtensionBuilder;
+use rss::{GuidBuilder, Item, ItemBuilder};
+
+use crate::provider::{self, MediaProvider};
+use crate::transcoder;
+
+/// urls of the list, scored by the time they were added
+const ORDER_KEY: &str = "listen_later";
+/// the rss items of the urls, serialized as json
+const ITEMS_KEY: &str = "listen_later_items";
+
+/// the list is not a cache, these keys must survive the flush of redis on a new version
+pub const PERSISTENT_KEYS: [&str; 2] = [ORDER_KEY, ITEMS_KEY];
+
+/// Adds an episode to the list, the metadata is fetched once here so that the feed is fast to
+/// generate. Adding an url again moves it to the top of the list
+pub async fn add(url: &Url) -> eyre::Result<()> {
+    info!("adding {} to listen later", url);
+    let provider = provider::from(url);
+    let mut item = if provider.is_episode_url(url) {
+        provider.fetch_episode(url).await?
+    } else {
+        fetch_media_item(&provider, url).await?
+    };
+    // podcast apps sort by date, the episodes are sorted by when they were added
+    let added_at = Utc::now();
+    item.set_pub_date(added_at.to_rfc2822());
+
+    let mut redis = crate::get_redis_client().await?;
+    () = redis::pipe()
+        .atomic()
+        .cmd("ZADD")
+        .arg(ORDER_KEY)
+        .arg(added_at.timestamp())
+        .arg(url.as_str())
+        .ignore()
+        .cmd("HSET")
+        .arg(ITEMS_KEY)
+        .arg(url.as_str())
+        .arg(serde_json::to_string(&item)?)
+        .ignore()
+        .query_async(&mut redis)
+        .await?;
+    Ok(())
+}
+
+/// returns false if the url was not in the list
+pub async fn remove(url: &Url) -> eyre::Result<bool> {
+    info!("removing {} from listen later", url);
+    let mut redis = crate::get_redis_client().await?;
+    let (removed, _): (usize, usize) = redis::pipe()
+        .atomic()
+        .cmd("ZREM")
+        .arg(ORDER_KEY)
+        .arg(url.as_str())
+        .cmd("HDEL")
+        .arg(ITEMS_KEY)
+        .arg(url.as_str())
+        .query_async(&mut redis)
+        .await?;
+    Ok(removed > 0)
+}
+
+/// the feed with the episodes of the list, the last added first
+pub async fn generate_rss_feed(feed_url: &Url) -> eyre::Result<String> {
+    let mut redis = crate::get_redis_client().await?;
+    let urls: Vec<String> = redis::cmd("ZREVRANGE")
+        .arg(ORDER_KEY)
+        .arg(0)
+        .arg(-1)
+        .query_async(&mut redis)
+        .await?;
+    debug!("listen later urls: {:?}", urls);
+    let raw_items: Vec<Option<String>> = if urls.is_empty() {
+        Vec::new()
+    } else {
+        redis::cmd("HMGET")
+            .arg(ITEMS_KEY)
+            .arg(&urls)
+            .query_async(&mut redis)
+            .await?
+    };
+    let items: Vec<Item> = urls
+        .iter()
+        .zip(raw_items)
+        .filter_map(|(url, raw_item)| {
+            match serde_json::from_str(raw_item.as_deref().unwrap_or_default()) {
+                Ok(item) => Some(item),
+                Err(e) => {
+                    warn!("skipping corrupted listen later item {}: {}", url, e);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    let mut feed_builder = provider::build_default_rss_structure();
+    feed_builder
+        .title("Listen later")
+        .description("episodes saved to listen later")
+        .link(feed_url.to_string())
+        .items(items);
+    Ok(feed_builder.build().to_string())
+}
+
+/// for urls that are not an episode of a provider (es: a link to an mp3) only the duration can
+/// be found, by probing the stream
+async fn fetch_media_item(provider: &provider::Provider, url: &Url) -> eyre::Result<Item> {
+    let stream_url = provider.get_stream_url(url).await?;
+    let duration_secs = transcoder::probe_duration(&stream_url).await?;
+    let title = media_title(url).ok_or_else(|| eyre!("{} has no title", url))?;
+
+    let mut item_builder = ItemBuilder::default();
+    item_builder
+        .title(Some(title))
+        .link(Some(url.to_string()))
+        .guid(Some(GuidBuilder::default().value(url.to_string()).build()))
+        .itunes_ext(Some(
+            ITunesItemExtensionBuilder::default()
+                .duration(Some(format!(
+                    "{:02}:{:02}:{:02}",
+                    duration_secs / 3600,
+                    duration_secs / 60 % 60,
+                    duration_secs % 60
+                )))
+                .build(),
+        ));
+    Ok(item_builder.build())
+}
+
+/// the file name, or the host for urls without a path
+fn media_title(url: &Url) -> Option<String> {
+    let file_name = url
+        .path_segments()
+        .and_then(|mut segments| segments.rfind(|segment| !segment.is_empty()))
+        .map(provider::decode_path_segment);
+    file_name.or_else(|| url.host_str().map(str::to_string))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_media_title() {
+        let url = Url::parse("https://example.com/podcasts/my%20episode.mp3?t=1").unwrap();
+        assert_eq!(media_title(&url).as_deref(), Some("my episode.mp3"));
+        let url = Url::parse("https://example.com/").unwrap();
+        assert_eq!(media_title(&url).as_deref(), Some("example.com"));
+    }
+}
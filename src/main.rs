@@ -3,7 +3,7 @@ use simple_logger::SimpleLogger;
 use std::{env, net::TcpListener, process::exit};
 use vod2pod_rss::{
     configs::{conf, Conf, ConfName},
-    server,
+    listen_later, server,
 };
 
 #[actix_web::main]
@@ -59,7 +59,22 @@ async fn flush_redis_on_new_version() -> eyre::Result<()> {
     if let Some(ref cached_version) = cached_version {
         if cached_version != app_version {
             info!("detected version change ({cached_version} != {app_version}) flushing redis DB");
+            let mut persistent_values = Vec::new();
+            for key in listen_later::PERSISTENT_KEYS {
+                let value: Option<Vec<u8>> =
+                    redis::cmd("DUMP").arg(key).query_async(&mut con).await?;
+                persistent_values.extend(value.map(|value| (key, value)));
+            }
             () = redis::cmd("FLUSHDB").query_async(&mut con).await?;
+            for (key, value) in persistent_values {
+                debug!("restoring {key} after flush");
+                () = redis::cmd("RESTORE")
+                    .arg(key)
+                    .arg(0)
+                    .arg(value)
+                    .query_async(&mut con)
+                    .await?;
+            }
         }
     }
 
@@ -1,8 +1,17 @@
+use std::collections::HashMap;
+
 use async_trait::async_trait;
+use feed_rs::model::{Entry, Feed, Link};
+use log::{debug, info, warn};
 use regex::Regex;
 use reqwest::Url;
+use rss::{
+    extension::itunes::{ITunesChannelExtensionBuilder, ITunesItemExtensionBuilder},
+    Channel, Enclosure, GuidBuilder, ImageBuilder, Item, ItemBuilder,
+};
 
 use crate::configs::{conf, Conf, ConfName};
+use crate::provider;
 
 use super::MediaProvider;
 
@@ -11,7 +20,19 @@ pub struct GenericProvider;
 #[async_trait]
 impl MediaProvider for GenericProvider {
     async fn generate_rss_feed(&self, channel_url: Url) -> eyre::Result<String> {
-        Ok(reqwest::get(channel_url).await?.text().await?)
+        let body = reqwest::get(channel_url.clone()).await?.bytes().await?;
+        // rss is passed as it is, the other formats (atom, json feed) are converted to rss
+        let feed = match Channel::read_from(&body[..]) {
+            Ok(_) => String::from_utf8_lossy(&body).into_owned(),
+            Err(_) => {
+                info!("{} is not an rss feed, converting it to rss", channel_url);
+                convert_feed_to_rss(feed_rs::parser::parse(&body[..])?).to_string()
+            }
+        };
+        if let Err(e) = allow_media_urls(&feed).await {
+            warn!("could not whitelist the enclosures: {}", e);
+        }
+        Ok(feed)
     }
 
     async fn get_stream_url(&self, media_url: &Url) -> eyre::Result<Url> {
@@ -23,6 +44,218 @@ impl MediaProvider for GenericProvider {
     }
 }
 
+fn convert_feed_to_rss(feed: Feed) -> Channel {
+    let image = feed.logo.or(feed.icon).map(|image| image.uri);
+    let mut feed_builder = provider::build_default_rss_structure();
+    feed_builder
+        .title(feed.title.map(|title| title.content).unwrap_or_default())
+        .description(
+            feed.description
+                .map(|description| description.content)
+                .unwrap_or_default(),
+        )
+        .link(
+            feed.links
+                .iter()
+                .find(|link| link.rel.as_deref() != Some("self"))
+                .map(|link| link.href.clone())
+                .unwrap_or_default(),
+        )
+        .image(
+            image
+                .as_ref()
+                .map(|image| ImageBuilder::default().url(image).build()),
+        )
+        .itunes_ext(Some(
+            ITunesChannelExtensionBuilder::default()
+                .image(image)
+                .author(feed.authors.first().map(|author| author.name.clone()))
+                .build(),
+        ))
+        .items(
+            feed.entries
+                .into_iter()
+                .map(convert_entry_to_item)
+                .collect::<Vec<_>>(),
+        );
+    feed_builder.build()
+}
+
+/// atom enclosures are links with rel="enclosure", json feed attachments are links with an
+/// audio or video type
+fn is_media_link(link: &Link) -> bool {
+    link.rel.as_deref() == Some("enclosure")
+        || link.media_type.as_deref().is_some_and(|media_type| {
+            media_type.starts_with("audio/") || media_type.starts_with("video/")
+        })
+}
+
+fn convert_entry_to_item(entry: Entry) -> Item {
+    let enclosure = entry
+        .links
+        .iter()
+        .find(|link| is_media_link(link))
+        .map(|link| Enclosure {
+            url: link.href.clone(),
+            length: link.length.unwrap_or_default().to_string(),
+            mime_type: link
+                .media_type
+                .clone()
+                .unwrap_or_else(|| "audio/mpeg".to_string()),
+        })
+        .or_else(|| {
+            entry
+                .media
+                .iter()
+                .flat_map(|media| &media.content)
+                .find_map(|content| {
+                    Some(Enclosure {
+                        url: content.url.as_ref()?.to_string(),
+                        length: content.size.unwrap_or_default().to_string(),
+                        mime_type: content
+                            .content_type
+                            .as_ref()
+                            .map_or_else(|| "audio/mpeg".to_string(), |t| t.to_string()),
+                    })
+                })
+        });
+    let link = entry
+        .links
+        .iter()
+        .find(|link| {
+            !is_media_link(link) && link.rel.as_deref().is_none_or(|rel| rel == "alternate")
+        })
+        .map(|link| link.href.clone());
+    let duration = entry.media.iter().find_map(|media| {
+        media
+            .duration
+            .or_else(|| media.content.iter().find_map(|content| content.duration))
+    });
+    let image = entry
+        .media
+        .iter()
+        .flat_map(|media| &media.thumbnails)
+        .next()
+        .map(|thumbnail| thumbnail.image.uri.clone());
+    let description = entry
+        .summary
+        .map(|summary| summary.content)
+        .or_else(|| entry.content.and_then(|content| content.body));
+
+    let mut item_builder = ItemBuilder::default();
+    item_builder
+        .title(entry.title.map(|title| title.content))
+        .link(link)
+        .guid(Some(
+            GuidBuilder::default()
+                .value(entry.id)
+                .permalink(false)
+                .build(),
+        ))
+        .pub_date(
+            entry
+                .published
+                .or(entry.updated)
+                .map(|date| date.to_rfc2822()),
+        )
+        .author(entry.authors.first().map(|author| author.name.clone()))
+        .description(description.clone())
+        .enclosure(enclosure)
+        .itunes_ext(Some(
+            ITunesItemExtensionBuilder::default()
+                .summary(description)
+                .image(image)
+                .duration(duration.map(|duration| {
+                    let secs = duration.as_secs();
+                    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+                }))
+                .build(),
+        ));
+    item_builder.build()
+}
+
+/// how long the enclosures stay whitelisted after the feed was last generated
+const MEDIA_URL_TTL_SECONDS: u64 = 7 * 24 * 3600;
+
+/// podcasts often host their audio files on a different domain than the feed (es: a CDN), the
+/// enclosures of a whitelisted feed are whitelisted too so that they can be transcoded. Only the
+/// exact urls are whitelisted, and only when they don't point to the internal network
+async fn allow_media_urls(feed: &str) -> eyre::Result<()> {
+    let channel = Channel::read_from(feed.as_bytes())?;
+    let mut urls: Vec<Url> = channel
+        .items()
+        .iter()
+        .filter_map(|item| Url::parse(item.enclosure()?.url()).ok())
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .collect();
+    urls.sort();
+    urls.dedup();
+
+    // the episodes are usually all on the same host, it's resolved only once
+    let mut public_origins = HashMap::new();
+    let mut allowed_urls = Vec::with_capacity(urls.len());
+    for url in urls {
+        let origin = url.origin().ascii_serialization();
+        let is_public = match public_origins.get(&origin) {
+            Some(is_public) => *is_public,
+            None => {
+                let is_public = match provider::resolve_public_addresses(&url).await {
+                    Ok(_) => true,
+                    Err(e) => {
+                        warn!("not whitelisting the enclosures of {origin}: {e}");
+                        false
+                    }
+                };
+                public_origins.insert(origin, is_public);
+                is_public
+            }
+        };
+        if is_public {
+            allowed_urls.push(url);
+        }
+    }
+    if allowed_urls.is_empty() {
+        return Ok(());
+    }
+    debug!("whitelisting enclosures {:?}", allowed_urls);
+
+    let mut redis = crate::get_redis_client().await?;
+    let mut pipe = redis::pipe();
+    for url in allowed_urls {
+        pipe.cmd("SET")
+            .arg(format!("allowed_media_url={url}"))
+            .arg(1)
+            .arg("EX")
+            .arg(MEDIA_URL_TTL_SECONDS)
+            .ignore();
+    }
+    () = pipe.query_async(&mut redis).await?;
+    Ok(())
+}
+
+/// true if the url is an enclosure of a generated generic feed, its host is resolved again in
+/// case it changed address since
+pub async fn is_media_url_allowed(url: &Url) -> bool {
+    let Ok(mut redis) = crate::get_redis_client().await else {
+        return false;
+    };
+    let whitelisted: bool = redis::cmd("EXISTS")
+        .arg(format!("allowed_media_url={url}"))
+        .query_async(&mut redis)
+        .await
+        .unwrap_or_default();
+    if !whitelisted {
+        return false;
+    }
+    match provider::resolve_public_addresses(url).await {
+        Ok(_) => true,
+        Err(e) => {
+            warn!("refusing whitelisted media url: {e}");
+            false
+        }
+    }
+}
+
 fn get_generic_whitelist() -> Vec<Regex> {
     let binding = conf().get(ConfName::ValidUrlDomains).unwrap();
     let patterns: Vec<&str> = binding
@@ -42,3 +275,70 @@ fn get_generic_whitelist() -> Vec<Regex> {
     regexes
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_atom_to_rss() {
+        let atom = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>podcast</title>
+  <link href="https://example.com/"/>
+  <logo>https://example.com/logo.png</logo>
+  <id>urn:podcast</id>
+  <updated>2023-05-14T10:00:00Z</updated>
+  <entry>
+    <title>episode 1</title>
+    <id>urn:episode-1</id>
+    <updated>2023-05-14T10:00:00Z</updated>
+    <link rel="alternate" href="https://example.com/episode-1"/>
+    <link rel="enclosure" type="audio/mpeg" length="1000" href="https://cdn.example.com/episode-1.mp3"/>
+    <summary>the first episode</summary>
+  </entry>
+</feed>"#;
+        let channel = convert_feed_to_rss(feed_rs::parser::parse(atom.as_bytes()).unwrap());
+
+        assert_eq!(channel.title(), "podcast");
+        assert_eq!(
+            channel.image().unwrap().url(),
+            "https://example.com/logo.png"
+        );
+        let item = &channel.items()[0];
+        assert_eq!(item.title(), Some("episode 1"));
+        assert_eq!(item.link(), Some("https://example.com/episode-1"));
+        assert_eq!(item.description(), Some("the first episode"));
+        assert_eq!(item.pub_date(), Some("Sun, 14 May 2023 10:00:00 +0000"));
+        let enclosure = item.enclosure().unwrap();
+        assert_eq!(enclosure.url(), "https://cdn.example.com/episode-1.mp3");
+        assert_eq!(enclosure.length(), "1000");
+    }
+
+    #[test]
+    fn test_convert_json_feed_to_rss() {
+        let json_feed = r#"{
+            "version": "https://jsonfeed.org/version/1.1",
+            "title": "podcast",
+            "home_page_url": "https://example.com/",
+            "items": [{
+                "id": "1",
+                "url": "https://example.com/episode-1",
+                "title": "episode 1",
+                "content_text": "the first episode",
+                "date_published": "2023-05-14T10:00:00Z",
+                "attachments": [{
+                    "url": "https://cdn.example.com/episode-1.m4a",
+                    "mime_type": "audio/x-m4a",
+                    "size_in_bytes": 1000
+                }]
+            }]
+        }"#;
+        let channel = convert_feed_to_rss(feed_rs::parser::parse(json_feed.as_bytes()).unwrap());
+
+        let item = &channel.items()[0];
+        assert_eq!(item.link(), Some("https://example.com/episode-1"));
+        let enclosure = item.enclosure().unwrap();
+        assert_eq!(enclosure.url(), "https://cdn.example.com/episode-1.m4a");
+        assert_eq!(enclosure.mime_type(), "audio/x-m4a");
+    }
+}
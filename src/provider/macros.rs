@@ -42,6 +42,18 @@ impl MediaProvider for $name {
         provider_dispatcher!($name, self $(,$provider)* ; get_stream_url(&media_url).await);
     }
 
+    async fn refresh_stream_url(&self, media_url: &Url) -> eyre::Result<Url> {
+        provider_dispatcher!($name, self $(,$provider)* ; refresh_stream_url(&media_url).await);
+    }
+
+    fn is_episode_url(&self, url: &Url) -> bool {
+        provider_dispatcher!($name, self $(,$provider)* ; is_episode_url(&url));
+    }
+
+    async fn fetch_episode(&self, episode_url: &Url) -> eyre::Result<Item> {
+        provider_dispatcher!($name, self $(,$provider)* ; fetch_episode(&episode_url).await);
+    }
+
     fn domain_whitelist_regexes(&self) -> Vec<Regex> {
         provider_dispatcher!($name, self $(,$provider)* ; domain_whitelist_regexes());
     }
@@ -60,6 +72,16 @@ pub enum $name {
     $provider($provider),
     )*
 }
+
+impl $name {
+    /// name of the provider in use, es: YoutubeProvider
+    pub fn name(&self) -> &'static str {
+        match self {
+            $(
+            $name::$provider(_) => stringify!($provider),
+            )*
+        }
+    }
+}
     };
 }
-
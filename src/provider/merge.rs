@@ -0,0 +1,301 @@
+use std::collections::{BTreeMap, HashSet};
+
+use chrono::{DateTime, FixedOffset};
+use eyre::eyre;
+use futures::future::join_all;
+use log::{debug, warn};
+use reqwest::Url;
+use rss::{Channel, ImageBuilder, Item};
+use serde::{Deserialize, Serialize};
+
+use super::{build_default_rss_structure, MediaProvider};
+use crate::configs::{conf, Conf, ConfName};
+
+/// items of different sources published this close with a similar title are the same episode
+/// (es: a Twitch vod uploaded later on YouTube)
+const DUPLICATE_MAX_DAYS_APART: i64 = 3;
+/// share of words two titles must have in common to be considered the same
+const TITLE_SIMILARITY_THRESHOLD: f64 = 0.8;
+
+/// Sources combined in a single feed, can be saved with a name in FEED_GROUPS
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FeedGroup {
+    pub urls: Vec<Url>,
+    pub title: Option<String>,
+    pub image: Option<Url>,
+}
+
+/// the group saved in FEED_GROUPS, es: {"name": {"urls": ["https://..."], "title": "..."}}
+pub fn saved_feed_group(name: &str) -> eyre::Result<FeedGroup> {
+    let mut groups: BTreeMap<String, FeedGroup> =
+        serde_json::from_str(&conf().get(ConfName::FeedGroups)?).map_err(|e| {
+            eyre!(r#"failed to parse FEED_GROUPS allowed syntax is {{"name": {{"urls": ["url1", "url2", ...]}}}}: {e}"#)
+        })?;
+    groups
+        .remove(name)
+        .ok_or_else(|| eyre!("no feed group named {name}"))
+}
+
+/// Generates the feeds of all the sources concurrently and merges them, sources that fail are
+/// left out of the feed
+pub async fn generate_merged_feed(group: &FeedGroup) -> eyre::Result<String> {
+    let feeds = join_all(group.urls.iter().map(|url| async move {
+        let provider = super::from(url);
+        let raw_rss = if provider.is_episode_url(url) {
+            super::generate_episode_feed(&provider, url).await?
+        } else {
+            provider.generate_rss_feed(url.clone()).await?
+        };
+        Ok::<_, eyre::Error>(Channel::read_from(raw_rss.as_bytes())?)
+    }))
+    .await;
+
+    let channels: Vec<Channel> = group
+        .urls
+        .iter()
+        .zip(feeds)
+        .filter_map(|(url, feed)| match feed {
+            Ok(channel) => Some(channel),
+            Err(e) => {
+                warn!("leaving {url} out of the merged feed: {e}");
+                None
+            }
+        })
+        .collect();
+    if channels.is_empty() {
+        return Err(eyre!("no source of the merged feed could be generated"));
+    }
+
+    let link = group.urls.first().map(Url::to_string).unwrap_or_default();
+    Ok(merge_channels(channels, group, &link).to_string())
+}
+
+/// The items of the channels, newest first, when the same episode is in more channels the one of
+/// the first channel is kept
+fn merge_channels(channels: Vec<Channel>, group: &FeedGroup, link: &str) -> Channel {
+    let titles: Vec<&str> = channels.iter().map(Channel::title).collect();
+    let title = group.title.clone().unwrap_or_else(|| titles.join(" + "));
+    let image = group.image.as_ref().map(Url::to_string).or_else(|| {
+        channels.iter().find_map(|channel| {
+            channel
+                .itunes_ext()
+                .and_then(|itunes| itunes.image())
+                .or_else(|| channel.image().map(|image| image.url()))
+                .map(str::to_string)
+        })
+    });
+    let author = channels
+        .iter()
+        .find_map(|channel| channel.itunes_ext().and_then(|itunes| itunes.author()))
+        .map(str::to_string);
+
+    let mut feed_builder = build_default_rss_structure();
+    feed_builder
+        .title(&title)
+        .link(link)
+        .description(format!("{} combined by vod2pod-rss", titles.join(", ")))
+        .image(
+            image
+                .as_ref()
+                .map(|image| ImageBuilder::default().url(image).title(&title).build()),
+        );
+    let mut feed = feed_builder.build();
+    if let Some(ref mut itunes) = feed.itunes_ext {
+        itunes.set_image(image);
+        itunes.set_author(author);
+    }
+
+    // the index of the source is kept with each item
+    let mut items: Vec<(usize, Item)> = Vec::new();
+    for (source, mut channel) in channels.into_iter().enumerate() {
+        // namespaces of the items extensions (es: media: of some generic feeds)
+        for (prefix, uri) in channel.namespaces() {
+            feed.namespaces
+                .entry(prefix.clone())
+                .or_insert_with(|| uri.clone());
+        }
+        // live items of the sources
+        for (prefix, extensions) in std::mem::take(&mut channel.extensions) {
+            let merged = feed.extensions.entry(prefix).or_default();
+            for (name, mut values) in extensions {
+                merged.entry(name).or_default().append(&mut values);
+            }
+        }
+        for item in channel.items {
+            if let Some((_, duplicate)) = items
+                .iter()
+                .find(|(kept_source, kept)| is_same_episode(kept, &item, *kept_source == source))
+            {
+                debug!(
+                    "{:?} is a duplicate of {:?}, skipping it",
+                    item.title(),
+                    duplicate.title()
+                );
+                continue;
+            }
+            items.push((source, item));
+        }
+    }
+    // items without a date go last
+    items.sort_by_key(|(_, item)| std::cmp::Reverse(pub_date(item)));
+    feed.set_items(items.into_iter().map(|(_, item)| item).collect::<Vec<_>>());
+    feed
+}
+
+/// a source can have episodes with similar titles close to each other (es: daily streams with
+/// the same title), so only items of different sources are compared by title and date
+fn is_same_episode(a: &Item, b: &Item, same_source: bool) -> bool {
+    if a.guid().is_some() && a.guid().map(|g| g.value()) == b.guid().map(|g| g.value()) {
+        return true;
+    }
+    if a.link().is_some() && a.link() == b.link() {
+        return true;
+    }
+    if same_source {
+        return false;
+    }
+    let (Some(date_a), Some(date_b)) = (pub_date(a), pub_date(b)) else {
+        return false;
+    };
+    (date_a - date_b).num_days().abs() <= DUPLICATE_MAX_DAYS_APART
+        && title_similarity(a.title().unwrap_or_default(), b.title().unwrap_or_default())
+            >= TITLE_SIMILARITY_THRESHOLD
+}
+
+fn pub_date(item: &Item) -> Option<DateTime<FixedOffset>> {
+    item.pub_date()
+        .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+}
+
+/// the words in common over all the words of the titles, ignoring case and punctuation
+fn title_similarity(a: &str, b: &str) -> f64 {
+    fn words(title: &str) -> HashSet<String> {
+        title
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect()
+    }
+    let (a, b) = (words(a), words(b));
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    a.intersection(&b).count() as f64 / a.union(&b).count() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use rss::{ChannelBuilder, GuidBuilder, ItemBuilder};
+
+    use super::*;
+
+    fn item(title: &str, guid: &str, pub_date: &str) -> Item {
+        ItemBuilder::default()
+            .title(Some(title.to_string()))
+            .guid(Some(GuidBuilder::default().value(guid).build()))
+            .pub_date(Some(pub_date.to_string()))
+            .build()
+    }
+
+    #[test]
+    fn test_merge_channels() {
+        let twitch = ChannelBuilder::default()
+            .title("creator")
+            .items(vec![
+                item(
+                    "Building a compiler | part 3",
+                    "twitch-3",
+                    "Wed, 10 May 2023 20:00:00 +0000",
+                ),
+                item(
+                    "Just chatting",
+                    "twitch-2",
+                    "Mon, 08 May 2023 20:00:00 +0000",
+                ),
+            ])
+            .build();
+        let youtube = ChannelBuilder::default()
+            .title("creator VODs")
+            .items(vec![
+                item(
+                    "Building a compiler - Part 3",
+                    "yt-3",
+                    "Fri, 12 May 2023 09:00:00 +0000",
+                ),
+                item(
+                    "Building a compiler - Part 1",
+                    "yt-1",
+                    "Thu, 20 Apr 2023 09:00:00 +0000",
+                ),
+                item(
+                    "Just chatting",
+                    "twitch-2",
+                    "Mon, 08 May 2023 20:00:00 +0000",
+                ),
+            ])
+            .build();
+        let group = FeedGroup {
+            image: Some(Url::parse("https://example.com/cover.jpg").unwrap()),
+            ..Default::default()
+        };
+
+        let merged = merge_channels(vec![twitch, youtube], &group, "https://twitch.tv/creator");
+
+        assert_eq!(merged.title(), "creator + creator VODs");
+        assert_eq!(
+            merged.itunes_ext().and_then(|itunes| itunes.image()),
+            Some("https://example.com/cover.jpg")
+        );
+        let guids: Vec<&str> = merged
+            .items()
+            .iter()
+            .filter_map(|item| item.guid().map(|guid| guid.value()))
+            .collect();
+        assert_eq!(guids, vec!["twitch-3", "twitch-2", "yt-1"]);
+    }
+
+    #[test]
+    fn test_similar_items_of_the_same_source_are_kept() {
+        let twitch = ChannelBuilder::default()
+            .title("creator")
+            .items(vec![
+                item(
+                    "Just Chatting",
+                    "twitch-2",
+                    "Tue, 09 May 2023 20:00:00 +0000",
+                ),
+                item(
+                    "Just Chatting",
+                    "twitch-1",
+                    "Mon, 08 May 2023 20:00:00 +0000",
+                ),
+                item(
+                    "Just Chatting",
+                    "twitch-1",
+                    "Mon, 08 May 2023 20:00:00 +0000",
+                ),
+            ])
+            .build();
+
+        let merged = merge_channels(vec![twitch], &FeedGroup::default(), "");
+
+        let guids: Vec<&str> = merged
+            .items()
+            .iter()
+            .filter_map(|item| item.guid().map(|guid| guid.value()))
+            .collect();
+        assert_eq!(guids, vec!["twitch-2", "twitch-1"]);
+    }
+
+    #[test]
+    fn test_title_similarity() {
+        assert_eq!(title_similarity("Part 1!", "part 1"), 1.0);
+        assert!(
+            title_similarity(
+                "Building a compiler - Part 1",
+                "Building a compiler - Part 2"
+            ) < TITLE_SIMILARITY_THRESHOLD
+        );
+        assert_eq!(title_similarity("", ""), 0.0);
+    }
+}
@@ -1,21 +1,29 @@
 mod generic;
 #[macro_use]
 mod macros;
+mod merge;
 mod peertube;
 mod twitch;
 mod youtube;
 
+use std::net::{IpAddr, SocketAddr};
+
 use async_trait::async_trait;
 use log::debug;
 use regex::Regex;
 use reqwest::Url;
 use rss::extension::itunes::ITunesChannelExtensionBuilder;
+use rss::{ImageBuilder, Item};
+use url::Host;
 
 use crate::provider::{
     generic::GenericProvider, peertube::PeerTubeProvider, twitch::TwitchProvider,
     youtube::YoutubeProvider,
 };
 
+pub use generic::is_media_url_allowed;
+pub use merge::{generate_merged_feed, saved_feed_group, FeedGroup};
+
 // to add a new provider just add it here (the provider should implement the MediaProvider trait)
 generate_static_dispatcher!(
     Provider
@@ -48,6 +56,38 @@ pub trait MediaProvider {
     /// * `media_url` - The original URL found inside the RSS that should be streamed.
     async fn get_stream_url(&self, media_url: &Url) -> eyre::Result<Url>;
 
+    /// Same as get_stream_url but the result must never come from a cache, this is used when
+    /// a stream URL returned before stopped working (es: expired googlevideo URLs)
+    ///
+    /// providers that do not cache their stream URLs don't need to implement this
+    ///
+    /// # Arguments
+    ///
+    /// * `media_url` - The original URL found inside the RSS that should be streamed.
+    async fn refresh_stream_url(&self, media_url: &Url) -> eyre::Result<Url> {
+        self.get_stream_url(media_url).await
+    }
+
+    /// Returns true if the URL points to a single episode instead of a channel,
+    /// es: https://www.youtube.com/watch?v=UMO52N2vfk0
+    ///
+    /// providers that don't support single episode feeds don't need to implement this
+    fn is_episode_url(&self, _url: &Url) -> bool {
+        false
+    }
+
+    /// Fetches the metadata of a single episode, the item will be the only one of the feed
+    /// generated by generate_episode_feed
+    ///
+    /// # Arguments
+    ///
+    /// * `episode_url` - An URL for which is_episode_url returned true.
+    async fn fetch_episode(&self, episode_url: &Url) -> eyre::Result<Item> {
+        Err(eyre::eyre!(
+            "single episode feeds are not supported for {episode_url}"
+        ))
+    }
+
     /// Returns the regular expressions that will match all urls offered by the provider.
     /// This are the url associated with the provider
     /// es: for youtube you would need to match
@@ -64,6 +104,82 @@ pub trait MediaProvider {
     fn domain_whitelist_regexes(&self) -> Vec<Regex>;
 }
 
+/// Builds a feed with only the episode at `episode_url`, used when a single video is shared
+/// instead of a channel
+pub async fn generate_episode_feed(provider: &Provider, episode_url: &Url) -> eyre::Result<String> {
+    let item = provider.fetch_episode(episode_url).await?;
+    debug!("fetched episode: {:?}", item);
+    let image = item.itunes_ext().and_then(|itunes| itunes.image());
+
+    let mut feed_builder = build_default_rss_structure();
+    feed_builder
+        .title(item.title().unwrap_or_default())
+        .link(episode_url.to_string())
+        .description(item.description().unwrap_or_default())
+        .image(image.map(|image| ImageBuilder::default().url(image).build()));
+    let mut feed = feed_builder.build();
+    if let Some(ref mut itunes) = feed.itunes_ext {
+        itunes.set_image(image.map(str::to_string));
+        itunes.set_author(item.author().map(str::to_string));
+    }
+    feed.set_items(vec![item]);
+    Ok(feed.to_string())
+}
+
+/// Resolves the host of `url`, fails if any of its addresses is not public (loopback, private,
+/// link-local, ...) so that urls coming from feeds or query params can't reach the internal
+/// network
+pub async fn resolve_public_addresses(url: &Url) -> eyre::Result<Vec<SocketAddr>> {
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| eyre::eyre!("{url} has no port"))?;
+    let addresses: Vec<SocketAddr> = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        Some(Host::Domain(domain)) => tokio::net::lookup_host((domain, port)).await?.collect(),
+        None => return Err(eyre::eyre!("{url} has no host")),
+    };
+    if addresses.is_empty() {
+        return Err(eyre::eyre!("{url} does not resolve to any address"));
+    }
+    if let Some(address) = addresses.iter().find(|address| !is_public_ip(address.ip())) {
+        return Err(eyre::eyre!(
+            "{url} resolves to the non public address {address}"
+        ));
+    }
+    Ok(addresses)
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let is_shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || is_shared)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// path segments are percent encoded (es: Just%20Chatting), a `+` is kept as it is
+pub(crate) fn decode_path_segment(segment: &str) -> String {
+    percent_encoding::percent_decode_str(segment)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
 /// This is the default rss structure used as a base for all the providers,
 pub fn build_default_rss_structure() -> rss::ChannelBuilder {
     let mut feed_builder = rss::ChannelBuilder::default();
@@ -77,6 +193,15 @@ pub fn build_default_rss_structure() -> rss::ChannelBuilder {
         "itunes".to_string(),
         "http://www.itunes.com/dtds/podcast-1.0.dtd".to_string(),
     );
+    namespaces.insert(
+        "podcast".to_string(),
+        "https://podcastindex.org/namespace/1.0".to_string(),
+    );
+    // used by the providers to pass informations to rss_transcodizer, removed from the final feed
+    namespaces.insert(
+        "vod2pod".to_string(),
+        "https://github.com/madiele/vod2pod-rss".to_string(),
+    );
     feed_builder.namespaces(namespaces);
 
     feed_builder.generator(Some("generated by vod2pod-rss".to_string()));
@@ -90,6 +215,27 @@ pub fn build_default_rss_structure() -> rss::ChannelBuilder {
 
 #[cfg(test)]
 mod tests {
-    //use super::*;
-}
+    use super::*;
 
+    #[test]
+    fn test_is_public_ip() {
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
@@ -1,18 +1,21 @@
-use std::str::FromStr;
+use std::{collections::BTreeMap, str::FromStr};
 
 use async_trait::async_trait;
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, Utc};
 use log::{debug, info, warn};
 use redis::{FromRedisValue, ParsingError, ToRedisArgs};
 use regex::Regex;
-use reqwest::Url;
+use reqwest::{StatusCode, Url};
 use rss::{
-    extension::itunes::{ITunesChannelExtensionBuilder, ITunesItemExtensionBuilder},
+    extension::{
+        itunes::{ITunesChannelExtensionBuilder, ITunesItemExtensionBuilder},
+        Extension, ExtensionBuilder,
+    },
     GuidBuilder, ImageBuilder, Item, ItemBuilder,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::{
     configs::{conf, Conf, ConfName},
@@ -27,33 +30,29 @@ pub struct TwitchProvider;
 impl MediaProvider for TwitchProvider {
     async fn generate_rss_feed(&self, channel_url: Url) -> eyre::Result<String> {
         info!("trying to convert twitch channel url {}", channel_url);
-        let username = channel_url
+        let mut path_segments = channel_url
             .path_segments()
             .ok_or_else(|| eyre::eyre!("Unable to get path segments"))?
-            .last()
-            .ok_or_else(|| eyre::eyre!("Unable to get last path segment"))?;
+            .filter(|segment| !segment.is_empty());
+        let username = path_segments
+            .next()
+            .ok_or_else(|| eyre::eyre!("Unable to get username from path"))?;
+        if username == "directory" {
+            let category = parse_category(path_segments).ok_or_else(|| {
+                eyre::eyre!("Unable to get category from path, expected /directory/category/<name>")
+            })?;
+            return generate_category_rss_feed(&channel_url, category).await;
+        }
+        let is_clips_feed = path_segments.next() == Some("clips");
 
         debug!("parsed username {}", username);
 
-        let client_id = &conf().get(ConfName::TwitchClientId)?;
-        let client_secret = &conf().get(ConfName::TwitchSecretKey)?;
-        debug!("fetching oauth token");
-        let oauth_token = authorize(client_id, client_secret).await?.oauth_token;
-
-        // add bearer token to request
-        let client = reqwest::Client::new();
-        let channels = client
-            .get(format!(
-                "https://api.twitch.tv/helix/users?login={}",
-                username
-            ))
-            .bearer_auth(oauth_token.clone())
-            .header("Client-Id", client_id)
-            .send()
-            .await?
-            .json::<UserData>()
-            .await?
-            .data;
+        let max_results: usize = conf().get(ConfName::TwitchMaxResults)?.parse()?;
+
+        let client = HelixClient::new().await?;
+        let mut users_url = Url::parse("https://api.twitch.tv/helix/users")?;
+        users_url.query_pairs_mut().append_pair("login", username);
+        let channels = client.get::<UserData>(users_url).await?.data;
 
         let channel = channels
             .first()
@@ -61,39 +60,50 @@ impl MediaProvider for TwitchProvider {
 
         debug!("fetched twitch channel: {:?}", channel);
 
-        let vods_request = client
-            .get(format!(
-                "https://api.twitch.tv/helix/videos?user_id={}",
-                channel.id
-            ))
-            .bearer_auth(oauth_token.clone())
-            .header("Client-Id", client_id.clone())
-            .send();
-
-        let streams_request = client
-            .get(format!(
-                "https://api.twitch.tv/helix/streams?user_id={}",
-                channel.id
-            ))
-            .bearer_auth(oauth_token)
-            .header("Client-Id", client_id)
-            .send();
-
-        let (vods_response, streams_response) = tokio::join!(vods_request, streams_request);
+        let (title, rss_items, live_items) = if is_clips_feed {
+            let started_at = parse_clips_period(&channel_url)?;
+            let clips = fetch_clips(&client, &channel.id, started_at, max_results).await?;
+            debug!("fetched clips: {:?}", clips);
+            (
+                format!("{} clips", channel.display_name),
+                clips.into_iter().map(clip_to_rss_item_converter).collect(),
+                Vec::new(),
+            )
+        } else {
+            let video_type = parse_video_type(&channel_url)?;
+            let vods_request = fetch_vods(&client, &channel.id, video_type, max_results);
 
-        let vods_data: VodsData = vods_response?.json().await?;
-        let vods = vods_data.data;
+            let mut streams_url = Url::parse("https://api.twitch.tv/helix/streams")?;
+            streams_url
+                .query_pairs_mut()
+                .append_pair("user_id", &channel.id);
+            let streams_request = client.get::<StreamsData>(streams_url);
 
-        let streams_data: StreamsData = streams_response?.json().await?;
-        let streams = streams_data.data;
+            let (vods, streams_data) = tokio::join!(vods_request, streams_request);
+            let vods = vods?;
+            let streams = streams_data?.data;
 
-        debug!("fetched vods and streams: {:?}\n{:?}", vods, streams);
+            debug!("fetched vods and streams: {:?}\n{:?}", vods, streams);
+            let live_items = if is_live_item_enabled(&channel_url) {
+                streams
+                    .iter()
+                    .map(|stream| stream_to_live_item(stream, &channel.login))
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            (
+                channel.display_name.clone(),
+                build_items_from_vods(vods, streams, parse_muted_segments_mode(&channel_url)?),
+                live_items,
+            )
+        };
 
         let mut channel_builder = provider::build_default_rss_structure();
 
         let mut image_builder = ImageBuilder::default();
         channel_builder
-            .title(channel.display_name.clone())
+            .title(title)
             .link(channel_url.clone())
             .description(channel.description.clone())
             .itunes_ext(Some(
@@ -106,7 +116,11 @@ impl MediaProvider for TwitchProvider {
                 image_builder.url(channel.profile_image_url.clone()).build(),
             ));
 
-        let rss_items = build_items_from_vods(vods, streams);
+        if !live_items.is_empty() {
+            let mut live_item_extensions = BTreeMap::new();
+            live_item_extensions.insert("liveItem".to_string(), live_items);
+            channel_builder.extension(("podcast".to_string(), live_item_extensions));
+        }
 
         Ok(channel_builder.items(rss_items).build().to_string())
     }
@@ -115,6 +129,31 @@ impl MediaProvider for TwitchProvider {
         get_twitch_stream_url(media_url).await
     }
 
+    fn is_episode_url(&self, url: &Url) -> bool {
+        parse_vod_id(url).is_some()
+    }
+
+    async fn fetch_episode(&self, episode_url: &Url) -> eyre::Result<Item> {
+        let vod_id = parse_vod_id(episode_url)
+            .ok_or_else(|| eyre::eyre!("no vod id found in {}", episode_url))?;
+        info!("fetching twitch vod {}", vod_id);
+        let client = HelixClient::new().await?;
+        let mut videos_url = Url::parse("https://api.twitch.tv/helix/videos")?;
+        videos_url.query_pairs_mut().append_pair("id", vod_id);
+        let vod = client
+            .get::<HelixPage<Video>>(videos_url)
+            .await?
+            .data
+            .into_iter()
+            .next()
+            .ok_or_else(|| eyre::eyre!("No twitch vod found with id {}", vod_id))?;
+        debug!("fetched twitch vod: {:?}", vod);
+        let author = vod.user_name.clone();
+        let mut item = vod_to_rss_item_converter(vod, parse_muted_segments_mode(episode_url)?);
+        item.set_author(Some(author));
+        Ok(item)
+    }
+
     fn domain_whitelist_regexes(&self) -> Vec<Regex> {
         let twitch_whitelist = vec![
             regex::Regex::new(r"^https?://(.*\.)?twitch\.tv/").unwrap(),
@@ -153,6 +192,13 @@ struct UserData {
 }
 
 #[allow(dead_code)]
+#[derive(Deserialize, Debug)]
+struct Category {
+    id: String,
+    name: String,
+    box_art_url: String,
+}
+
 #[derive(Deserialize, Debug)]
 struct MutedSegments {
     pub duration: i32,
@@ -212,16 +258,453 @@ struct StreamsData {
 
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
-struct VodsData {
-    pub data: Vec<Video>,
+struct Clip {
+    id: String,
+    url: String,
+    broadcaster_id: String,
+    broadcaster_name: String,
+    creator_id: String,
+    creator_name: String,
+    video_id: String,
+    game_id: String,
+    language: String,
+    title: String,
+    view_count: i32,
+    created_at: String,
+    thumbnail_url: String,
+    duration: f32,
+}
+
+/// a page of results of the helix api, `pagination.cursor` points to the next page
+#[derive(Deserialize, Debug)]
+struct HelixPage<T> {
+    pub data: Vec<T>,
     pub pagination: serde_json::Value,
 }
 
+/// es: https://www.twitch.tv/videos/1234567890
+fn parse_vod_id(url: &Url) -> Option<&str> {
+    let mut segments = url.path_segments()?.filter(|s| !s.is_empty());
+    if segments.next()? != "videos" {
+        return None;
+    }
+    segments
+        .next()
+        .filter(|id| id.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// the video type can be chosen by adding `?type=archive|highlight|upload|all` to the channel url
+fn parse_video_type(channel_url: &Url) -> eyre::Result<&'static str> {
+    let video_type = channel_url
+        .query_pairs()
+        .find(|(key, _)| key == "type")
+        .map(|(_, value)| value.to_lowercase());
+    match video_type.as_deref() {
+        None | Some("all") => Ok("all"),
+        Some("archive") => Ok("archive"),
+        Some("highlight") => Ok("highlight"),
+        Some("upload") => Ok("upload"),
+        Some(other) => Err(eyre::eyre!(
+            "unknown twitch video type {other}, use one of: all, archive, highlight, upload"
+        )),
+    }
+}
+
+/// feed of the vods of a category (es: https://www.twitch.tv/directory/category/just-chatting)
+///
+/// the category url accepts these query params:
+/// * `language` - only vods in this language (es: `en`)
+/// * `period` - `day`, `week`, `month` or `all` (default: `week`)
+/// * `sort` - `time`, `trending` or `views` (default: `time`)
+/// * `streamers` - comma separated list of logins, only their vods are kept
+/// * `type` and `muted` - same as for channel feeds
+async fn generate_category_rss_feed(category_url: &Url, category: &str) -> eyre::Result<String> {
+    let category = provider::decode_path_segment(category);
+    debug!("parsed category {}", category);
+    let query_param = |name: &str| {
+        category_url
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.to_lowercase())
+    };
+    let period = query_param("period").unwrap_or_else(|| "week".to_string());
+    if !["day", "week", "month", "all"].contains(&period.as_str()) {
+        return Err(eyre::eyre!(
+            "unknown period {period}, use one of: day, week, month, all"
+        ));
+    }
+    let sort = query_param("sort").unwrap_or_else(|| "time".to_string());
+    if !["time", "trending", "views"].contains(&sort.as_str()) {
+        return Err(eyre::eyre!(
+            "unknown sort {sort}, use one of: time, trending, views"
+        ));
+    }
+    let streamers: Vec<String> = query_param("streamers")
+        .map(|streamers| {
+            streamers
+                .split(',')
+                .map(|streamer| streamer.trim().to_string())
+                .filter(|streamer| !streamer.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    let video_type = parse_video_type(category_url)?;
+    let muted_segments_mode = parse_muted_segments_mode(category_url)?;
+    let max_results: usize = conf().get(ConfName::TwitchMaxResults)?.parse()?;
+
+    let client = HelixClient::new().await?;
+    let category = find_category(&client, &category).await?;
+    debug!("found twitch category: {:?}", category);
+
+    let mut videos_url = Url::parse("https://api.twitch.tv/helix/videos")?;
+    videos_url
+        .query_pairs_mut()
+        .append_pair("game_id", &category.id)
+        .append_pair("period", &period)
+        .append_pair("sort", &sort)
+        .append_pair("type", video_type);
+    if let Some(language) = query_param("language") {
+        videos_url
+            .query_pairs_mut()
+            .append_pair("language", &language);
+    }
+    // the streamers filter is applied after fetching, so more pages are needed to fill the feed
+    let fetched_results = if streamers.is_empty() {
+        max_results
+    } else {
+        max_results * 10
+    };
+    let vods: Vec<Video> = fetch_helix_pages(&client, videos_url, fetched_results)
+        .await?
+        .into_iter()
+        .filter(|vod: &Video| {
+            streamers.is_empty() || streamers.contains(&vod.user_login.to_lowercase())
+        })
+        .take(max_results)
+        .collect();
+    debug!("fetched category vods: {:?}", vods);
+
+    let rss_items = vods
+        .into_iter()
+        .map(|mut vod| {
+            vod.title = format!("{}: {}", vod.user_name, vod.title);
+            vod_to_rss_item_converter(vod, muted_segments_mode)
+        })
+        .collect::<Vec<_>>();
+
+    let box_art_url = category
+        .box_art_url
+        .replace("{width}", "600")
+        .replace("{height}", "800");
+    let mut channel_builder = provider::build_default_rss_structure();
+    let mut image_builder = ImageBuilder::default();
+    channel_builder
+        .title(category.name.clone())
+        .link(category_url.to_string())
+        .description(format!("Twitch vods of {}", category.name))
+        .itunes_ext(Some(
+            ITunesChannelExtensionBuilder::default()
+                .image(Some(box_art_url.clone()))
+                .author(Some("Twitch".to_string()))
+                .build(),
+        ))
+        .image(Some(image_builder.url(box_art_url).build()));
+
+    Ok(channel_builder.items(rss_items).build().to_string())
+}
+
+/// the category in the url is a slug (es: just-chatting), so it's searched by name and the
+/// result with the same slug is preferred
+async fn find_category(client: &HelixClient, category: &str) -> eyre::Result<Category> {
+    let mut search_url = Url::parse("https://api.twitch.tv/helix/search/categories")?;
+    search_url
+        .query_pairs_mut()
+        .append_pair("query", &category.replace('-', " "))
+        .append_pair("first", "20");
+    let mut categories = client.get::<HelixPage<Category>>(search_url).await?.data;
+    let slug = category_slug(category);
+    // the search also returns similar categories, a feed of the wrong one is worse than none
+    let index = categories
+        .iter()
+        .position(|candidate| category_slug(&candidate.name) == slug)
+        .ok_or_else(|| eyre::eyre!("No twitch category found for {category}"))?;
+    Ok(categories.swap_remove(index))
+}
+
+/// the category in the path after `/directory`, es: `category/just-chatting/videos/all` or the
+/// older `game/Just%20Chatting`
+fn parse_category<'a>(mut path_segments: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    match path_segments.next()? {
+        "category" | "game" => path_segments.next(),
+        _ => None,
+    }
+}
+
+fn category_slug(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// the clips period can be chosen by adding `?period=24hr|7d|30d|all` (or twitch's own `range`)
+/// to the clips url, returns the date the period starts at
+fn parse_clips_period(clips_url: &Url) -> eyre::Result<Option<DateTime<Utc>>> {
+    let period = clips_url
+        .query_pairs()
+        .find(|(key, _)| key == "period" || key == "range")
+        .map(|(_, value)| value.to_lowercase());
+    let days = match period.as_deref() {
+        None | Some("7d") => 7,
+        Some("24h") | Some("24hr") => 1,
+        Some("30d") => 30,
+        Some("all") => return Ok(None),
+        Some(other) => {
+            return Err(eyre::eyre!(
+                "unknown twitch clips period {other}, use one of: 24hr, 7d, 30d, all"
+            ))
+        }
+    };
+    Ok(Some(Utc::now() - chrono::Duration::days(days)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MutedSegmentsMode {
+    /// the muted segments are left in the episode
+    Keep,
+    /// the muted segments are marked with chapters
+    Chapters,
+    /// the muted segments are cut out while transcoding
+    Cut,
+}
+
+/// chosen by adding `?muted=keep|chapters|cut` to the channel url
+fn parse_muted_segments_mode(channel_url: &Url) -> eyre::Result<MutedSegmentsMode> {
+    let mode = channel_url
+        .query_pairs()
+        .find(|(key, _)| key == "muted")
+        .map(|(_, value)| value.to_lowercase());
+    match mode.as_deref() {
+        None | Some("keep") => Ok(MutedSegmentsMode::Keep),
+        Some("chapters") => Ok(MutedSegmentsMode::Chapters),
+        Some("cut") => Ok(MutedSegmentsMode::Cut),
+        Some(other) => Err(eyre::eyre!(
+            "unknown muted segments mode {other}, use one of: keep, chapters, cut"
+        )),
+    }
+}
+
+/// the ongoing stream is added as a `podcast:liveItem` by adding `?live=true` to the channel url
+fn is_live_item_enabled(channel_url: &Url) -> bool {
+    channel_url
+        .query_pairs()
+        .any(|(key, value)| key == "live" && value.eq_ignore_ascii_case("true"))
+}
+
+async fn fetch_vods(
+    client: &HelixClient,
+    user_id: &str,
+    video_type: &str,
+    max_results: usize,
+) -> eyre::Result<Vec<Video>> {
+    let mut url = Url::parse("https://api.twitch.tv/helix/videos")?;
+    url.query_pairs_mut()
+        .append_pair("user_id", user_id)
+        .append_pair("type", video_type);
+    fetch_helix_pages(client, url, max_results).await
+}
+
+async fn fetch_clips(
+    client: &HelixClient,
+    broadcaster_id: &str,
+    started_at: Option<DateTime<Utc>>,
+    max_results: usize,
+) -> eyre::Result<Vec<Clip>> {
+    let url = clips_url(broadcaster_id, started_at, Utc::now())?;
+    fetch_helix_pages(client, url, max_results).await
+}
+
+/// helix ends the range a week after `started_at` when `ended_at` is missing
+fn clips_url(
+    broadcaster_id: &str,
+    started_at: Option<DateTime<Utc>>,
+    ended_at: DateTime<Utc>,
+) -> eyre::Result<Url> {
+    let mut url = Url::parse("https://api.twitch.tv/helix/clips")?;
+    url.query_pairs_mut()
+        .append_pair("broadcaster_id", broadcaster_id);
+    if let Some(started_at) = started_at {
+        url.query_pairs_mut()
+            .append_pair(
+                "started_at",
+                started_at
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                    .as_str(),
+            )
+            .append_pair(
+                "ended_at",
+                ended_at
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                    .as_str(),
+            );
+    }
+    Ok(url)
+}
+
+async fn fetch_helix_pages<T: DeserializeOwned>(
+    client: &HelixClient,
+    base_url: Url,
+    max_results: usize,
+) -> eyre::Result<Vec<T>> {
+    const MAX_PAGE_SIZE: usize = 100;
+    let mut results = Vec::new();
+    let mut cursor: Option<String> = None;
+
+    while results.len() < max_results {
+        let page_size = (max_results - results.len()).min(MAX_PAGE_SIZE);
+        let mut url = base_url.clone();
+        url.query_pairs_mut()
+            .append_pair("first", page_size.to_string().as_str());
+        if let Some(ref cursor) = cursor {
+            url.query_pairs_mut().append_pair("after", cursor);
+        }
+
+        debug!("fetching twitch page {}", url);
+        let page: HelixPage<T> = client.get(url).await?;
+
+        let fetched_count = page.data.len();
+        results.extend(page.data);
+
+        cursor = page.pagination["cursor"].as_str().map(str::to_string);
+        if cursor.is_none() || fetched_count == 0 {
+            break;
+        }
+    }
+
+    results.truncate(max_results);
+    Ok(results)
+}
+
+/// Client for the helix api, it re-authorizes when the cached token gets revoked and retries
+/// with exponential backoff when twitch is failing or rate limiting
+struct HelixClient {
+    client: reqwest::Client,
+    client_id: String,
+    client_secret: String,
+    oauth_token: std::sync::Mutex<String>,
+}
+
+impl HelixClient {
+    const MAX_ATTEMPTS: u32 = 4;
+    const MAX_WAIT: Duration = Duration::from_secs(60);
+
+    async fn new() -> eyre::Result<Self> {
+        let client_id = conf().get(ConfName::TwitchClientId)?;
+        let client_secret = conf().get(ConfName::TwitchSecretKey)?;
+        debug!("fetching oauth token");
+        let oauth_token = authorize(&client_id, &client_secret).await?.oauth_token;
+        Ok(Self {
+            client: reqwest::Client::new(),
+            client_id,
+            client_secret,
+            oauth_token: std::sync::Mutex::new(oauth_token),
+        })
+    }
+
+    async fn get<T: DeserializeOwned>(&self, url: Url) -> eyre::Result<T> {
+        let mut reauthorized = false;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let oauth_token = self.oauth_token.lock().unwrap().clone();
+            let response = self
+                .client
+                .get(url.clone())
+                .bearer_auth(oauth_token)
+                .header("Client-Id", &self.client_id)
+                .send()
+                .await;
+
+            let wait = match response {
+                Ok(response) if response.status().is_success() => {
+                    return Ok(response.json().await?)
+                }
+                Ok(response) if response.status() == StatusCode::UNAUTHORIZED && !reauthorized => {
+                    warn!("twitch rejected the oauth token, authorizing again");
+                    reauthorized = true;
+                    invalidate_credentials().await?;
+                    let credentials = authorize(&self.client_id, &self.client_secret).await?;
+                    *self.oauth_token.lock().unwrap() = credentials.oauth_token;
+                    attempt -= 1;
+                    continue;
+                }
+                Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                    rate_limit_reset_wait(&response).unwrap_or_else(|| backoff(attempt))
+                }
+                Ok(response) if !response.status().is_server_error() => {
+                    return Err(eyre::eyre!(
+                        "twitch request {} failed with status {}: {}",
+                        url,
+                        response.status(),
+                        response.text().await.unwrap_or_default()
+                    ));
+                }
+                Ok(response) => {
+                    warn!(
+                        "twitch request {} failed with status {}",
+                        url,
+                        response.status()
+                    );
+                    backoff(attempt)
+                }
+                Err(e) => {
+                    warn!("twitch request {} failed: {}", url, e);
+                    backoff(attempt)
+                }
+            };
+
+            if attempt >= Self::MAX_ATTEMPTS {
+                return Err(eyre::eyre!(
+                    "twitch request {} failed after {} attempts",
+                    url,
+                    attempt
+                ));
+            }
+            let wait = wait.min(Self::MAX_WAIT);
+            debug!("retrying twitch request {} in {:?}", url, wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// 500ms, 1s, 2s, 4s, ...
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_millis(500 * 2u64.pow(attempt.saturating_sub(1).min(10)))
+}
+
+/// twitch sends the epoch at which the rate limit bucket is refilled in `Ratelimit-Reset`
+fn rate_limit_reset_wait(response: &reqwest::Response) -> Option<Duration> {
+    let reset_epoch: u64 = response
+        .headers()
+        .get("Ratelimit-Reset")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    warn!(
+        "twitch rate limit reached, waiting {} seconds",
+        reset_epoch.saturating_sub(now)
+    );
+    Some(Duration::from_secs(reset_epoch.saturating_sub(now).max(1)))
+}
+
 async fn get_twitch_stream_url(url: &Url) -> eyre::Result<Url> {
     debug!("getting stream_url for twitch video: {}", url);
     let output = tokio::process::Command::new("yt-dlp")
         .arg("-f")
-        .arg("bestaudio")
+        .arg("bestaudio/best") // clips have no audio only format
         .arg("--get-url")
         .arg(url.as_str())
         .output()
@@ -235,8 +718,7 @@ async fn get_twitch_stream_url(url: &Url) -> eyre::Result<Url> {
                 Err(e) => {
                     warn!(
                         "error while parsing stream url:\ninput: {}\nerror: {}",
-                        raw_url,
-                        e.to_string()
+                        raw_url, e
                     );
                     Err(eyre::eyre!(e))
                 }
@@ -262,9 +744,8 @@ impl OAuthCredentials {
 impl FromRedisValue for OAuthCredentials {
     fn from_redis_value(v: redis::Value) -> Result<Self, ParsingError> {
         let raw_json = String::from_redis_value(v)?;
-        let credentials: OAuthCredentials = serde_json::from_str(&raw_json).map_err(|_e| {
-            ParsingError::from("redis twitch credential corrupted")
-        })?;
+        let credentials: OAuthCredentials = serde_json::from_str(&raw_json)
+            .map_err(|_e| ParsingError::from("redis twitch credential corrupted"))?;
         Ok(credentials)
     }
 }
@@ -279,6 +760,15 @@ impl ToRedisArgs for OAuthCredentials {
     }
 }
 
+async fn invalidate_credentials() -> eyre::Result<()> {
+    let mut redis = crate::get_redis_client().await?;
+    () = redis::cmd("DEL")
+        .arg(OAuthCredentials::key())
+        .query_async(&mut redis)
+        .await?;
+    Ok(())
+}
+
 async fn authorize(client_id: &str, client_secret: &str) -> eyre::Result<OAuthCredentials> {
     let mut redis = crate::get_redis_client().await?;
 
@@ -353,10 +843,23 @@ async fn authorize(client_id: &str, client_secret: &str) -> eyre::Result<OAuthCr
                 response.text().await?
             ));
         }
+        let wait = rate_limit_reset_wait(&response)
+            .unwrap_or_else(|| backoff(attempt))
+            .min(HelixClient::MAX_WAIT);
+        warn!(
+            "could not get OAuth token from Twitch (status {}), retrying in {:?}",
+            response.status(),
+            wait
+        );
+        tokio::time::sleep(wait).await;
     }
 }
 
-fn build_items_from_vods(vods: Vec<Video>, streams: Vec<Stream>) -> Vec<Item> {
+fn build_items_from_vods(
+    vods: Vec<Video>,
+    streams: Vec<Stream>,
+    muted_segments_mode: MutedSegmentsMode,
+) -> Vec<Item> {
     let streams = &streams;
     vods.into_iter()
         .filter(|v| {
@@ -364,16 +867,124 @@ fn build_items_from_vods(vods: Vec<Video>, streams: Vec<Stream>) -> Vec<Item> {
                 .iter()
                 .any(|s| v.stream_id.as_ref().is_some_and(|vid| *vid == s.id))
         })
-        .map(vod_to_rss_item_converter)
+        .map(|vod| vod_to_rss_item_converter(vod, muted_segments_mode))
         .collect()
 }
 
-fn vod_to_rss_item_converter(vod: Video) -> Item {
+/// the enclosure points to the channel, it is converted to the live transcode by
+/// rss_transcodizer, once the stream ends its vod will be listed as a normal item
+fn stream_to_live_item(stream: &Stream, login: &str) -> Extension {
+    let channel_link = format!("https://www.twitch.tv/{login}");
+    let child = |name: &str, value: &str| {
+        ExtensionBuilder::default()
+            .name(name)
+            .value(Some(value.to_string()))
+            .build()
+    };
+    let mut children = BTreeMap::new();
+    children.insert("title".to_string(), vec![child("title", &stream.title)]);
+    children.insert(
+        "description".to_string(),
+        vec![child(
+            "description",
+            &format!("{} is live playing {}", stream.user_name, stream.game_name),
+        )],
+    );
+    children.insert("guid".to_string(), vec![child("guid", &stream.id)]);
+    children.insert("link".to_string(), vec![child("link", &channel_link)]);
+    children.insert(
+        "enclosure".to_string(),
+        vec![ExtensionBuilder::default()
+            .name("enclosure")
+            .attr(("url".to_string(), channel_link.clone()))
+            .attr(("type".to_string(), "audio/mpeg".to_string()))
+            .attr(("length".to_string(), "0".to_string()))
+            .build()],
+    );
+
+    ExtensionBuilder::default()
+        .name("podcast:liveItem")
+        .attr(("status".to_string(), "live".to_string()))
+        .attr(("start".to_string(), stream.started_at.clone()))
+        .children(children)
+        .build()
+}
+
+fn clip_to_rss_item_converter(clip: Clip) -> Item {
+    let description = format!("{}\n\nclipped by {}", clip.title, clip.creator_name);
+    let mut item_builder = ItemBuilder::default();
+    item_builder.title(Some(clip.title.clone()));
+    item_builder.description(Some(description.clone()));
+    item_builder.link(Some(clip.url));
+    item_builder.guid(Some(GuidBuilder::default().value(clip.id).build()));
+    item_builder.pub_date(Some(
+        match DateTime::parse_from_rfc3339(clip.created_at.as_str()) {
+            Ok(publish_date) => publish_date.to_rfc2822(),
+            Err(_) => {
+                warn!(
+                    "Using default DateTime due to parsing error. {} could not be parsed",
+                    clip.created_at
+                );
+                let default_date: DateTime<FixedOffset> = DateTime::default();
+                default_date.to_rfc2822()
+            }
+        },
+    ));
+    let duration_secs = clip.duration.ceil() as usize;
+    let itunes_item_extension = ITunesItemExtensionBuilder::default()
+        .summary(Some(description))
+        .author(Some(clip.creator_name))
+        .duration(Some(format_duration(duration_secs)))
+        .image(Some(clip.thumbnail_url))
+        .build();
+    item_builder.itunes_ext(Some(itunes_item_extension));
+    item_builder.build()
+}
+
+fn vod_to_rss_item_converter(vod: Video, muted_segments_mode: MutedSegmentsMode) -> Item {
     let video_id = vod.id;
     let title = vod.title;
-    let description = title.clone();
     let published_at = vod.created_at;
+
+    let duration_secs = parse_twitch_duration(&vod.duration).unwrap_or_else(|| {
+        warn!("vod {} has invalid duration {}", video_id, vod.duration);
+        0
+    });
+    let muted_ranges = muted_ranges(
+        vod.muted_segments.as_deref().unwrap_or_default(),
+        duration_secs,
+    );
+    let muted_secs: usize = muted_ranges.iter().map(|(start, end)| end - start).sum();
+
+    let mut description = title.clone();
     let mut item_builder = ItemBuilder::default();
+    let episode_duration_secs = match muted_segments_mode {
+        MutedSegmentsMode::Keep => duration_secs,
+        MutedSegmentsMode::Chapters => {
+            description.push_str(&muted_chapters(&muted_ranges, duration_secs));
+            duration_secs
+        }
+        MutedSegmentsMode::Cut => {
+            if !muted_ranges.is_empty() {
+                let cut = muted_ranges
+                    .iter()
+                    .map(|(start, end)| format!("{start}-{end}"))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let mut extensions = BTreeMap::new();
+                extensions.insert(
+                    "cut".to_string(),
+                    vec![ExtensionBuilder::default()
+                        .name("vod2pod:cut")
+                        .value(Some(cut))
+                        .build()],
+                );
+                item_builder.extension(("vod2pod".to_string(), extensions));
+            }
+            duration_secs - muted_secs
+        }
+    };
+
     item_builder.title(Some(title.clone()));
     item_builder.description(Some(description.clone()));
     item_builder.link(Some(format!("https://www.twitch.tv/videos/{video_id}")));
@@ -392,37 +1003,7 @@ fn vod_to_rss_item_converter(vod: Video) -> Item {
     ));
     let itunes_item_extension = ITunesItemExtensionBuilder::default()
         .summary(Some(description))
-        .duration(Some({
-            let duration_as_string = vod.duration.replace(['h', 'm'], ":").replace('s', "");
-
-            let duration_parts = duration_as_string
-                .split(':')
-                .map(|s| s.parse::<usize>())
-                .collect::<Result<Vec<_>, _>>()
-                .unwrap_or_else(|_e| {
-                    warn!(
-                        "could not parse duration for twitch stream: {:?}",
-                        duration_as_string
-                    );
-                    vec![0]
-                });
-
-            match duration_parts.len() {
-                3 => format!(
-                    "{:02}:{:02}:{:02}",
-                    duration_parts[0], duration_parts[1], duration_parts[2]
-                ),
-                2 => format!("00:{:02}:{:02}", duration_parts[0], duration_parts[1]),
-                1 => format!("00:00:{:02}", duration_parts[0]),
-                _ => {
-                    warn!(
-                        "vod {} has invalid duration {}",
-                        video_id, duration_as_string
-                    );
-                    String::from("00:00:00")
-                }
-            }
-        }))
+        .duration(Some(format_duration(episode_duration_secs)))
         .image(Some(
             vod.thumbnail_url
                 .replace("%{width}", "512")
@@ -433,11 +1014,192 @@ fn vod_to_rss_item_converter(vod: Video) -> Item {
     item_builder.build()
 }
 
+/// twitch durations look like 1h2m3s
+fn parse_twitch_duration(duration: &str) -> Option<usize> {
+    let duration_as_string = duration.replace(['h', 'm'], ":").replace('s', "");
+    let duration_parts = duration_as_string
+        .split(':')
+        .map(|s| s.parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+
+    match duration_parts[..] {
+        [hours, minutes, seconds] => Some(hours * 3600 + minutes * 60 + seconds),
+        [minutes, seconds] => Some(minutes * 60 + seconds),
+        [seconds] => Some(seconds),
+        _ => None,
+    }
+}
+
+fn format_duration(duration_secs: usize) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        duration_secs / 3600,
+        (duration_secs % 3600) / 60,
+        duration_secs % 60
+    )
+}
+
+/// sorted and merged (start, end) ranges of the muted segments
+fn muted_ranges(muted_segments: &[MutedSegments], duration_secs: usize) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = muted_segments
+        .iter()
+        .filter(|segment| segment.offset >= 0 && segment.duration > 0)
+        .map(|segment| {
+            let start = (segment.offset as usize).min(duration_secs);
+            let end = (start + segment.duration as usize).min(duration_secs);
+            (start, end)
+        })
+        .filter(|(start, end)| start < end)
+        .collect();
+    ranges.sort();
+
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// chapters in the description format picked up by rss_transcodizer
+fn muted_chapters(muted_ranges: &[(usize, usize)], duration_secs: usize) -> String {
+    let mut chapters = String::new();
+    if muted_ranges.first().is_some_and(|(start, _)| *start > 0) {
+        chapters.push_str(&format!("\n{} start", format_duration(0)));
+    }
+    for (start, end) in muted_ranges {
+        chapters.push_str(&format!("\n{} muted", format_duration(*start)));
+        if *end < duration_secs {
+            chapters.push_str(&format!("\n{} unmuted", format_duration(*end)));
+        }
+    }
+    chapters
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use test_log::test;
 
+    #[test]
+    fn test_parse_video_type() {
+        let url = Url::parse("https://www.twitch.tv/tumblurr").unwrap();
+        assert_eq!(parse_video_type(&url).unwrap(), "all");
+        let url = Url::parse("https://www.twitch.tv/tumblurr?type=Highlight").unwrap();
+        assert_eq!(parse_video_type(&url).unwrap(), "highlight");
+        let url = Url::parse("https://www.twitch.tv/tumblurr?type=clips").unwrap();
+        assert!(parse_video_type(&url).is_err());
+    }
+
+    #[test]
+    fn test_parse_clips_period() {
+        let url = Url::parse("https://www.twitch.tv/tumblurr/clips?period=all").unwrap();
+        assert!(parse_clips_period(&url).unwrap().is_none());
+        let url = Url::parse("https://www.twitch.tv/tumblurr/clips?range=30d").unwrap();
+        let started_at = parse_clips_period(&url).unwrap().unwrap();
+        assert_eq!((Utc::now() - started_at).num_days(), 30);
+        let url = Url::parse("https://www.twitch.tv/tumblurr/clips?period=1y").unwrap();
+        assert!(parse_clips_period(&url).is_err());
+    }
+
+    #[test]
+    fn test_clips_url() {
+        let ended_at = Utc::now();
+        let started_at = ended_at - chrono::Duration::days(30);
+        let url = clips_url("123", Some(started_at), ended_at).unwrap();
+        let query: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        let param = |key: &str| {
+            query
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| DateTime::parse_from_rfc3339(value).unwrap())
+        };
+        assert_eq!(
+            (param("ended_at").unwrap() - param("started_at").unwrap()).num_days(),
+            30
+        );
+
+        let url = clips_url("123", None, ended_at).unwrap();
+        assert_eq!(url.query(), Some("broadcaster_id=123"));
+    }
+
+    #[test]
+    fn test_parse_vod_id() {
+        let url = Url::parse("https://www.twitch.tv/videos/1234567890?muted=cut").unwrap();
+        assert_eq!(parse_vod_id(&url), Some("1234567890"));
+        let url = Url::parse("https://www.twitch.tv/videos").unwrap();
+        assert_eq!(parse_vod_id(&url), None);
+        let url = Url::parse("https://www.twitch.tv/channel/videos").unwrap();
+        assert_eq!(parse_vod_id(&url), None);
+    }
+
+    #[test]
+    fn test_parse_category() {
+        for (path, category) in [
+            ("category/just-chatting", Some("just-chatting")),
+            ("category/just-chatting/videos/all", Some("just-chatting")),
+            ("game/Just%20Chatting", Some("Just%20Chatting")),
+            ("category", None),
+            ("following", None),
+            ("just-chatting", None),
+        ] {
+            assert_eq!(parse_category(path.split('/')), category, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_category_slug() {
+        assert_eq!(category_slug("Just Chatting"), "just-chatting");
+        assert_eq!(
+            category_slug("Tom Clancy's Rainbow Six Siege"),
+            "tom-clancy-s-rainbow-six-siege"
+        );
+        assert_eq!(
+            provider::decode_path_segment("Just%20Chatting"),
+            "Just Chatting"
+        );
+        assert_eq!(
+            provider::decode_path_segment("Dungeons+Dragons"),
+            "Dungeons+Dragons"
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_millis(500));
+        assert_eq!(backoff(2), Duration::from_secs(1));
+        assert_eq!(backoff(4), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_muted_segments() {
+        let segments = vec![
+            MutedSegments {
+                offset: 600,
+                duration: 60,
+            },
+            MutedSegments {
+                offset: 30,
+                duration: 30,
+            },
+            MutedSegments {
+                offset: 640,
+                duration: 100,
+            },
+        ];
+        let ranges = muted_ranges(&segments, 700);
+        assert_eq!(ranges, vec![(30, 60), (600, 700)]);
+        assert_eq!(
+            muted_chapters(&ranges, 700),
+            "\n00:00:00 start\n00:00:30 muted\n00:01:00 unmuted\n00:10:00 muted"
+        );
+        assert_eq!(parse_twitch_duration("1h2m3s"), Some(3723));
+        assert_eq!(parse_twitch_duration("2m3s"), Some(123));
+    }
+
     #[test(tokio::test)]
     async fn fetch_twitch_channel_requires_api_key() {
         let provider = TwitchProvider;
@@ -2,22 +2,32 @@
 use cached::proc_macro::io_cached;
 #[allow(unused_imports)]
 use cached::AsyncRedisCache;
+use cached::IOCachedAsync;
 use feed_rs::model::Feed;
 use google_youtube3::{
-    api::{self, PlaylistItem},
+    api::{self, PlaylistItem, PlaylistItemSnippet},
     hyper, hyper_rustls, YouTube,
 };
-use std::{collections::HashMap, str::FromStr, time::Duration};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use async_trait::async_trait;
 use eyre::eyre;
+use futures::StreamExt;
 use log::{debug, info, warn};
 use regex::Regex;
 use reqwest::Url;
 use rss::{
-    extension::itunes::{ITunesChannelExtensionBuilder, ITunesItemExtensionBuilder},
+    extension::{
+        itunes::{ITunesChannelExtensionBuilder, ITunesItemExtensionBuilder},
+        ExtensionBuilder,
+    },
     Channel, ChannelBuilder, GuidBuilder, ImageBuilder, Item, ItemBuilder,
 };
+use serde::Deserialize;
 use tokio::process::Command;
 
 use crate::{
@@ -31,102 +41,120 @@ pub struct YoutubeProvider;
 
 enum IdType {
     Playlist(String),
-    Channel(String),
+    Channel(String, ChannelTab),
+}
+
+/// The tab of a channel page (es: https://www.youtube.com/@name/streams), the feed will only
+/// contain the videos of that tab
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChannelTab {
+    /// all the uploads of the channel
+    All,
+    Videos,
+    Streams,
+    Shorts,
+    Podcasts,
+}
+
+impl ChannelTab {
+    /// youtube has an automatic playlist for each tab, it's id is the channel id with the
+    /// "UC" prefix replaced
+    fn playlist_prefix(&self) -> Option<&'static str> {
+        match self {
+            ChannelTab::All => Some("UU"),
+            ChannelTab::Videos => Some("UULF"),
+            ChannelTab::Streams => Some("UULV"),
+            ChannelTab::Shorts => Some("UUSH"),
+            ChannelTab::Podcasts => None,
+        }
+    }
+}
+
+/// splits a channel url in the url of the channel and the selected tab
+fn parse_channel_tab(url: &Url) -> (Url, ChannelTab) {
+    let segments: Vec<&str> = url
+        .path_segments()
+        .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+    let channel_segments = match segments.first() {
+        Some(segment) if segment.starts_with('@') => 1,
+        Some(&"channel") | Some(&"c") | Some(&"user") => 2,
+        _ => return (url.clone(), ChannelTab::All),
+    };
+    let tab = match segments.get(channel_segments) {
+        Some(&"videos") => ChannelTab::Videos,
+        Some(&"streams") => ChannelTab::Streams,
+        Some(&"shorts") => ChannelTab::Shorts,
+        Some(&"podcasts") => ChannelTab::Podcasts,
+        _ => return (url.clone(), ChannelTab::All),
+    };
+    let mut channel_url = url.clone();
+    channel_url.set_path(&segments[..channel_segments].join("/"));
+    channel_url.set_query(None);
+    (channel_url, tab)
 }
 
 #[async_trait]
 impl MediaProvider for YoutubeProvider {
     async fn generate_rss_feed(&self, channel_url: Url) -> eyre::Result<String> {
-        let youtube_api_key = conf().get(ConfName::YoutubeApiKey).ok();
-
-        match youtube_api_key {
-            Some(api_key) => {
-                info!(
-                    "starting youtube feed generation for {} with API key",
-                    channel_url
-                );
-                let mut feed_builder = provider::build_default_rss_structure();
-
-                let id = match channel_url.path() {
-                    path if path.starts_with("/playlist") => {
-                        let playlist_id = channel_url
-                            .query_pairs()
-                            .find(|(key, _)| key == "list")
-                            .map(|(_, value)| value)
-                            .ok_or_else(|| {
-                                eyre::eyre!("Failed to parse playlist ID from URL: {}", channel_url)
-                            })?;
-                        IdType::Playlist(playlist_id.into())
-                    }
-                    path if path.starts_with("/channel/")
-                        || path.starts_with("/user/")
-                        || path.starts_with("/c/")
-                        || path.starts_with("/@") =>
-                    {
-                        let url = find_yt_channel_url_with_c_id(&channel_url).await?;
-                        let channel_id = url.path_segments().unwrap().last().unwrap();
-                        IdType::Channel(channel_id.into())
-                    }
-                    _ => return Err(eyre!("unsupported youtube url")),
-                };
+        let channel_url = &channel_url;
+        let search_query = &parse_search_query(channel_url);
+        let feed = with_api_keys(|api_key| async move {
+            match (search_query, api_key) {
+                (Some(search_query), api_key) => {
+                    generate_search_feed(channel_url, search_query, api_key).await
+                }
+                (None, Some(api_key)) => generate_api_feed(channel_url, api_key).await,
+                (None, None) => generate_keyless_feed(channel_url).await,
+            }
+        })
+        .await?;
 
-                let mut video_items = fetch_from_api(id, api_key).await?;
-
-                feed_builder.description(video_items.0.description);
-                feed_builder.title(video_items.0.title);
-                feed_builder.language(video_items.0.language.take());
-                let mut image_builder = ImageBuilder::default();
-                image_builder.url(
-                    video_items
-                        .0
-                        .itunes_ext
-                        .clone()
-                        .and_then(|it| it.image)
-                        .unwrap_or_default(),
-                );
-                feed_builder.image(Some(image_builder.build()));
-                feed_builder.itunes_ext(video_items.0.itunes_ext.take());
-                feed_builder.link(video_items.0.link);
+        let Some(audio_language) = parse_audio_language(channel_url) else {
+            return Ok(feed);
+        };
+        let mut feed = Channel::read_from(feed.as_bytes())?;
+        for item in feed.items_mut() {
+            set_audio_language(item, &audio_language);
+        }
+        Ok(feed.to_string())
+    }
 
-                feed_builder.items(video_items.1);
+    fn is_episode_url(&self, url: &Url) -> bool {
+        parse_video_id(url).is_some()
+    }
 
-                return Ok(feed_builder.build().to_string());
-            }
-            None => {
-                info!(
-                    "starting youtube feed generation for {} using atom feed",
-                    channel_url
-                );
-                let feed = match channel_url.path() {
-                    path if path.starts_with("/playlist") => {
-                        feed_url_for_yt_playlist(&channel_url).await
-                    }
-                    path if path.starts_with("/feeds/") => feed_url_for_yt_atom(&channel_url).await,
-                    path if path.starts_with("/channel/") => {
-                        feed_url_for_yt_channel(&channel_url).await
-                    }
-                    path if path.starts_with("/user/") => {
-                        feed_url_for_yt_channel(&channel_url).await
-                    }
-                    path if path.starts_with("/c/") => feed_url_for_yt_channel(&channel_url).await,
-                    path if path.starts_with("/@") => feed_url_for_yt_channel(&channel_url).await,
-                    _ => Err(eyre!("unsupported youtube url")),
-                }?;
-                let raw_atom_feed = reqwest::get(feed).await?.text().await?;
-                let feed = feed_rs::parser::parse(&raw_atom_feed.into_bytes()[..]).unwrap();
-                let mut duration_map: HashMap<String, Option<usize>> = HashMap::default();
-                for link in feed.clone().entries.iter().filter_map(|e| e.links.first()) {
-                    duration_map.insert(
-                        link.clone().href,
-                        get_youtube_video_duration_with_ytdlp(&link.href.parse::<Url>()?).await?,
-                    );
-                }
-                return Ok(convert_atom_to_rss(feed, duration_map));
+    async fn fetch_episode(&self, episode_url: &Url) -> eyre::Result<Item> {
+        let video_id = &parse_video_id(episode_url)
+            .ok_or_else(|| eyre!("no video id found in {}", episode_url))?;
+        info!("fetching youtube video {}", video_id);
+        let mut item = with_api_keys(|api_key| async move {
+            match api_key {
+                Some(api_key) => fetch_video_item(video_id, &api_key).await,
+                None => fetch_ytdlp_video_item(video_id).await,
             }
+        })
+        .await?;
+        if let Some(audio_language) = parse_audio_language(episode_url) {
+            set_audio_language(&mut item, &audio_language);
         }
+        Ok(item)
     }
 
     async fn get_stream_url(&self, media_url: &Url) -> eyre::Result<Url> {
+        let stream_url = get_youtube_stream_url(media_url).await?;
+        if is_stream_url_expired(&stream_url) {
+            info!("cached stream url for {} is about to expire", media_url);
+            return self.refresh_stream_url(media_url).await;
+        }
+        Ok(stream_url)
+    }
+
+    async fn refresh_stream_url(&self, media_url: &Url) -> eyre::Result<Url> {
+        info!("evicting cached stream url for {}", media_url);
+        if let Some(cache) = GET_YOUTUBE_STREAM_URL.get() {
+            cache.cache_remove(media_url).await?;
+        }
         get_youtube_stream_url(media_url).await
     }
 
@@ -150,6 +178,273 @@ impl MediaProvider for YoutubeProvider {
     }
 }
 
+/// every key gets a try, the ones out of quota are skipped until the quota resets, when none
+/// is left `generate` is called without a key
+async fn with_api_keys<T, F, Fut>(generate: F) -> eyre::Result<T>
+where
+    F: Fn(Option<String>) -> Fut,
+    Fut: std::future::Future<Output = eyre::Result<T>>,
+{
+    for _ in 0..youtube_api_keys().len() {
+        let Some(api_key) = next_youtube_api_key().await else {
+            break;
+        };
+        match generate(Some(api_key)).await {
+            Err(e) if e.is::<QuotaExceeded>() => warn!("{}, trying the next key", e),
+            result => return result,
+        }
+    }
+    generate(None).await
+}
+
+const AUDIO_LANGUAGE_PARAM: &str = "audio_lang";
+
+/// the audio track can be chosen by adding `?audio_lang=<language code>` (es: en, it, pt-BR) to the
+/// feed url, by default the original audio track is used instead of the dubbed ones
+fn parse_audio_language(url: &Url) -> Option<String> {
+    let (_, audio_language) = url
+        .query_pairs()
+        .find(|(key, _)| key == AUDIO_LANGUAGE_PARAM)?;
+    let is_valid = !audio_language.is_empty()
+        && audio_language
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !is_valid {
+        warn!(
+            "invalid audio language {:?}, using the original",
+            audio_language
+        );
+        return None;
+    }
+    (audio_language != "original").then(|| audio_language.into_owned())
+}
+
+/// the language is carried by the media url passed to rss_transcodizer (the link is left as it
+/// is, it's shown to the users), so that it reaches get_stream_url and is part of the key of the
+/// cached stream url
+fn set_audio_language(item: &mut Item, audio_language: &str) {
+    let Some(mut media_url) = item.link().and_then(|link| Url::parse(link).ok()) else {
+        return;
+    };
+    media_url
+        .query_pairs_mut()
+        .append_pair(AUDIO_LANGUAGE_PARAM, audio_language);
+    item.extensions
+        .entry("vod2pod".to_string())
+        .or_default()
+        .insert(
+            "media_url".to_string(),
+            vec![ExtensionBuilder::default()
+                .name("vod2pod:media_url")
+                .value(Some(media_url.to_string()))
+                .build()],
+        );
+}
+
+/// removes the audio language from a media url
+fn split_audio_language(url: &Url) -> (Url, Option<String>) {
+    let audio_language = parse_audio_language(url);
+    let mut video_url = url.clone();
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| key != AUDIO_LANGUAGE_PARAM)
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    if query.is_empty() {
+        video_url.set_query(None);
+    } else {
+        video_url.query_pairs_mut().clear().extend_pairs(query);
+    }
+    (video_url, audio_language)
+}
+
+/// the original track is marked as "original" in the format note, the dubbed ones are not
+fn audio_format_selector(audio_language: Option<&str>) -> String {
+    const ORIGINAL: &str = "bestaudio[format_note*=original]/bestaudio";
+    match audio_language {
+        Some(audio_language) => format!("bestaudio[language^={audio_language}]/{ORIGINAL}"),
+        None => ORIGINAL.to_string(),
+    }
+}
+
+/// es: https://www.youtube.com/watch?v=UMO52N2vfk0, https://youtu.be/UMO52N2vfk0 or
+/// https://www.youtube.com/shorts/UMO52N2vfk0
+fn parse_video_id(url: &Url) -> Option<String> {
+    let mut segments = url.path_segments()?.filter(|s| !s.is_empty());
+    let video_id = if url.host_str()?.ends_with("youtu.be") {
+        segments.next()?.to_string()
+    } else {
+        match segments.next()? {
+            "watch" => url
+                .query_pairs()
+                .find(|(key, _)| key == "v")
+                .map(|(_, value)| value.into_owned())?,
+            "shorts" | "live" => segments.next()?.to_string(),
+            _ => return None,
+        }
+    };
+    (!video_id.is_empty()).then_some(video_id)
+}
+
+async fn fetch_video_item(video_id: &str, api_key: &str) -> eyre::Result<Item> {
+    let hub = get_youtube_hub();
+    let video_request = hub
+        .videos()
+        .list(&vec!["snippet".into()])
+        .add_id(video_id)
+        .param("key", api_key);
+    let (_, response) = with_quota(api_key, LIST_QUOTA_COST, video_request.doit()).await?;
+    let snippet = response
+        .items
+        .and_then(|videos| videos.into_iter().next())
+        .and_then(|video| video.snippet)
+        .ok_or(eyre!("youtube returned no video with id {:?}", video_id))?;
+    let items = vec![PlaylistItem {
+        snippet: Some(PlaylistItemSnippet {
+            resource_id: Some(api::ResourceId {
+                video_id: Some(video_id.to_string()),
+                ..Default::default()
+            }),
+            title: snippet.title,
+            description: snippet.description,
+            published_at: snippet.published_at,
+            thumbnails: snippet.thumbnails,
+            channel_title: snippet.channel_title,
+            ..Default::default()
+        }),
+        ..Default::default()
+    }];
+    let duration_map = create_duration_url_map(&items, api_key).await?;
+    build_channel_items_from_playlist(items, duration_map)
+        .pop()
+        .ok_or_else(|| eyre!("video {} is not available as a VOD", video_id))
+}
+
+async fn fetch_ytdlp_video_item(video_id: &str) -> eyre::Result<Item> {
+    let video_url = youtube_video_url(video_id);
+    debug!("fetching {} with yt-dlp", video_url);
+    let output = Command::new("yt-dlp")
+        .arg("-J")
+        .arg("--ignore-no-formats-error")
+        .arg(&video_url)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "yt-dlp could not fetch {}: {}",
+            video_url,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let entry: YtDlpEntry = serde_json::from_slice(&output.stdout)?;
+    convert_ytdlp_entries_to_items(vec![entry])
+        .await
+        .pop()
+        .ok_or_else(|| eyre!("video {} is not available as a VOD", video_id))
+}
+
+async fn generate_api_feed(channel_url: &Url, api_key: String) -> eyre::Result<String> {
+    info!(
+        "starting youtube feed generation for {} with API key",
+        channel_url
+    );
+    let mut feed_builder = provider::build_default_rss_structure();
+
+    let id = match channel_url.path() {
+        path if path.starts_with("/playlist") => {
+            let playlist_id = channel_url
+                .query_pairs()
+                .find(|(key, _)| key == "list")
+                .map(|(_, value)| value)
+                .ok_or_else(|| {
+                    eyre::eyre!("Failed to parse playlist ID from URL: {}", channel_url)
+                })?;
+            IdType::Playlist(playlist_id.into())
+        }
+        path if path.starts_with("/channel/")
+            || path.starts_with("/user/")
+            || path.starts_with("/c/")
+            || path.starts_with("/@") =>
+        {
+            let (url, tab) = parse_channel_tab(channel_url);
+            if tab == ChannelTab::Podcasts {
+                return generate_podcasts_tab_feed(channel_url, Some(&api_key)).await;
+            }
+            let url = find_yt_channel_url_with_c_id(&url).await?;
+            let channel_id = url.path_segments().unwrap().next_back().unwrap();
+            IdType::Channel(channel_id.into(), tab)
+        }
+        _ => return Err(eyre!("unsupported youtube url")),
+    };
+
+    let mut video_items = fetch_from_api(id, api_key).await?;
+
+    feed_builder.description(video_items.0.description);
+    feed_builder.title(video_items.0.title);
+    feed_builder.language(video_items.0.language.take());
+    let mut image_builder = ImageBuilder::default();
+    image_builder.url(
+        video_items
+            .0
+            .itunes_ext
+            .clone()
+            .and_then(|it| it.image)
+            .unwrap_or_default(),
+    );
+    feed_builder.image(Some(image_builder.build()));
+    feed_builder.itunes_ext(video_items.0.itunes_ext.take());
+    feed_builder.link(video_items.0.link);
+
+    feed_builder.items(video_items.1);
+
+    Ok(feed_builder.build().to_string())
+}
+
+async fn generate_keyless_feed(channel_url: &Url) -> eyre::Result<String> {
+    let (_, tab) = parse_channel_tab(channel_url);
+    match tab {
+        ChannelTab::All => match generate_ytdlp_feed(channel_url).await {
+            Ok(feed) => return Ok(feed),
+            Err(e) => warn!(
+                "could not list {} with yt-dlp, falling back to the atom feed: {}",
+                channel_url, e
+            ),
+        },
+        ChannelTab::Podcasts => return generate_podcasts_tab_feed(channel_url, None).await,
+        _ => {
+            info!(
+                "starting youtube feed generation for {} using yt-dlp",
+                channel_url
+            );
+            let max_fetched_items: usize = conf().get(ConfName::YoutubeMaxResults)?.parse()?;
+            let playlist = fetch_ytdlp_playlist(channel_url.as_str(), max_fetched_items).await?;
+            return Ok(convert_ytdlp_playlist_to_rss(playlist).await);
+        }
+    }
+    info!(
+        "starting youtube feed generation for {} using atom feed",
+        channel_url
+    );
+    let feed = match channel_url.path() {
+        path if path.starts_with("/playlist") => feed_url_for_yt_playlist(channel_url).await,
+        path if path.starts_with("/feeds/") => feed_url_for_yt_atom(channel_url).await,
+        path if path.starts_with("/channel/") => feed_url_for_yt_channel(channel_url).await,
+        path if path.starts_with("/user/") => feed_url_for_yt_channel(channel_url).await,
+        path if path.starts_with("/c/") => feed_url_for_yt_channel(channel_url).await,
+        path if path.starts_with("/@") => feed_url_for_yt_channel(channel_url).await,
+        _ => Err(eyre!("unsupported youtube url")),
+    }?;
+    let raw_atom_feed = reqwest::get(feed).await?.text().await?;
+    let feed = feed_rs::parser::parse(&raw_atom_feed.into_bytes()[..]).unwrap();
+    let links = feed
+        .entries
+        .iter()
+        .filter_map(|e| e.links.first()?.href.parse::<Url>().ok())
+        .collect();
+    let duration_map = lookup_durations_with_ytdlp(links).await;
+    Ok(convert_atom_to_rss(feed, duration_map))
+}
+
 async fn fetch_from_api(id: IdType, api_key: String) -> eyre::Result<(Channel, Vec<Item>)> {
     match id {
         IdType::Playlist(id) => {
@@ -170,8 +465,8 @@ async fn fetch_from_api(id: IdType, api_key: String) -> eyre::Result<(Channel, V
 
             Ok((rss_channel, rss_items))
         }
-        IdType::Channel(id) => {
-            info!("fetching channel {}", id);
+        IdType::Channel(id, tab) => {
+            info!("fetching channel {} tab {:?}", id, tab);
             let mut channel = fetch_channel(id, &api_key).await?;
 
             let upload_playlist = channel
@@ -182,6 +477,16 @@ async fn fetch_from_api(id: IdType, api_key: String) -> eyre::Result<(Channel, V
                 .ok_or(eyre!("related_playlists is None"))?
                 .uploads
                 .ok_or(eyre!("uploads is None"))?;
+            let upload_playlist = match tab.playlist_prefix() {
+                Some(prefix) => format!(
+                    "{}{}",
+                    prefix,
+                    upload_playlist
+                        .strip_prefix("UU")
+                        .unwrap_or(&upload_playlist)
+                ),
+                None => return Err(eyre!("the {:?} tab has no playlist", tab)),
+            };
 
             let rss_channel = build_channel_from_yt_channel(channel);
 
@@ -241,7 +546,7 @@ async fn fetch_channel(id: String, api_key: &str) -> eyre::Result<api::Channel>
         .max_results(1)
         .add_id(&id)
         .param("key", api_key);
-    let result = channel_request.doit().await?;
+    let result = with_quota(api_key, LIST_QUOTA_COST, channel_request.doit()).await?;
     let channel = result
         .1
         .items
@@ -255,8 +560,53 @@ async fn fetch_channel(id: String, api_key: &str) -> eyre::Result<api::Channel>
 #[derive(Debug, Clone)]
 struct VideoExtraInfo {
     duration: iso8601_duration::Duration,
+    live_status: LiveStatus,
+}
+
+/// live streams and premieres have no duration until they are over, so they are left out of
+/// the feeds until they become a normal VOD
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LiveStatus {
+    NotLive,
+    Live,
+    Upcoming,
 }
 
+impl LiveStatus {
+    fn from_api(details: Option<&api::VideoLiveStreamingDetails>) -> Self {
+        match details {
+            Some(details) if details.actual_end_time.is_some() => LiveStatus::NotLive,
+            Some(details) if details.actual_start_time.is_some() => LiveStatus::Live,
+            Some(details) if details.scheduled_start_time.is_some() => LiveStatus::Upcoming,
+            _ => LiveStatus::NotLive,
+        }
+    }
+
+    /// post_live is a stream that just ended and is still being processed
+    fn from_ytdlp(live_status: &str) -> Self {
+        match live_status {
+            "is_live" | "post_live" => LiveStatus::Live,
+            "is_upcoming" => LiveStatus::Upcoming,
+            _ => LiveStatus::NotLive,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NotAVod(LiveStatus);
+
+impl std::fmt::Display for NotAVod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            LiveStatus::Live => write!(f, "the video is live"),
+            LiveStatus::Upcoming => write!(f, "the video is upcoming"),
+            LiveStatus::NotLive => write!(f, "the video is a VOD"),
+        }
+    }
+}
+
+impl std::error::Error for NotAVod {}
+
 async fn create_duration_url_map(
     items: &[PlaylistItem],
     api_key: &str,
@@ -270,13 +620,16 @@ async fn create_duration_url_map(
     let videos_requests = ids_batches.map(|batch| {
         let mut video_info_request = hub
             .videos()
-            .list(&vec!["contentDetails".to_owned()])
+            .list(&vec![
+                "contentDetails".to_owned(),
+                "liveStreamingDetails".to_owned(),
+            ])
             .param("key", api_key);
 
         for video_id in batch {
             video_info_request = video_info_request.add_id(&video_id);
         }
-        return video_info_request.doit();
+        with_quota(api_key, LIST_QUOTA_COST, video_info_request.doit())
     });
 
     info!(
@@ -288,7 +641,8 @@ async fn create_duration_url_map(
     let video_infos = futures::future::join_all(videos_requests)
         .await
         .into_iter()
-        .flatten()
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
         .map(|r| {
             r.0.status()
                 .is_success()
@@ -302,8 +656,9 @@ async fn create_duration_url_map(
             Some((
                 v.id?,
                 VideoExtraInfo {
+                    live_status: LiveStatus::from_api(v.live_streaming_details.as_ref()),
                     duration: iso8601_duration::Duration::parse(&v.content_details?.duration?)
-                        .unwrap(),
+                        .ok()?,
                 },
             ))
         })
@@ -339,6 +694,14 @@ fn build_channel_items_from_playlist(
                 warn!("no duration found for {:?}", &video_id);
                 None
             })?;
+            if video_infos.live_status != LiveStatus::NotLive {
+                info!(
+                    "skipping {}, {}",
+                    video_id,
+                    NotAVod(video_infos.live_status)
+                );
+                return None;
+            }
             let itunes_item_extension = ITunesItemExtensionBuilder::default()
                 .summary(Some(description))
                 .duration(Some({
@@ -357,8 +720,8 @@ fn build_channel_items_from_playlist(
 }
 
 async fn fetch_playlist_items(
-    playlist_id: &String,
-    api_key: &String,
+    playlist_id: &str,
+    api_key: &str,
     max_fetched_items: usize,
 ) -> eyre::Result<Vec<PlaylistItem>> {
     let hub = get_youtube_hub();
@@ -386,7 +749,7 @@ async fn fetch_playlist_items(
             playlist_items_request = playlist_items_request.page_token(next_page_token.as_str());
         }
 
-        let response = playlist_items_request.doit().await?;
+        let response = with_quota(api_key, LIST_QUOTA_COST, playlist_items_request.doit()).await?;
 
         fetched_playlist_items.extend(
             response
@@ -435,14 +798,14 @@ fn build_channel_from_playlist(playlist: api::Playlist) -> Channel {
     channel_builder.build()
 }
 
-async fn fetch_playlist(id: String, api_key: &String) -> Result<api::Playlist, eyre::Error> {
+async fn fetch_playlist(id: String, api_key: &str) -> Result<api::Playlist, eyre::Error> {
     let hub = get_youtube_hub();
     let playlist_request = hub
         .playlists()
         .list(&vec!["snippet".into()])
         .add_id(&id)
         .param("key", api_key);
-    let result = playlist_request.doit().await?;
+    let result = with_quota(api_key, LIST_QUOTA_COST, playlist_request.doit()).await?;
     let playlist = result
         .1
         .items
@@ -465,6 +828,156 @@ fn get_youtube_hub() -> YouTube<hyper_rustls::HttpsConnector<hyper::client::Http
     YouTube::new(client, auth)
 }
 
+/// cost in quota units of a list request, see https://developers.google.com/youtube/v3/determine_quota_cost
+const LIST_QUOTA_COST: u64 = 1;
+const SEARCH_QUOTA_COST: u64 = 100;
+
+#[derive(Debug)]
+struct QuotaExceeded(String);
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "youtube API quota exceeded for key {}", self.0)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// YT_API_KEY can hold multiple keys separated by commas
+fn youtube_api_keys() -> Vec<String> {
+    conf()
+        .get(ConfName::YoutubeApiKey)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// only the end of the key is used in logs and in redis
+fn api_key_id(api_key: &str) -> String {
+    let id: String = api_key.chars().rev().take(6).collect();
+    id.chars().rev().collect()
+}
+
+/// the quota resets at midnight pacific time, daylight saving time is ignored
+fn quota_day_and_seconds_to_reset() -> (String, u64) {
+    let pacific_time = chrono::Utc::now() - chrono::Duration::hours(8);
+    let day = pacific_time.date_naive();
+    let next_day = day
+        .succ_opt()
+        .and_then(|next_day| next_day.and_hms_opt(0, 0, 0))
+        .unwrap_or_default();
+    let seconds_to_reset = (next_day - pacific_time.naive_utc()).num_seconds().max(1) as u64;
+    (day.to_string(), seconds_to_reset)
+}
+
+/// picks the keys round-robin, skipping the ones that ran out of quota for the day
+async fn next_youtube_api_key() -> Option<String> {
+    let api_keys = youtube_api_keys();
+    if api_keys.len() <= 1 {
+        let api_key = api_keys.into_iter().next()?;
+        return (!is_quota_exhausted(&api_key).await).then_some(api_key);
+    }
+    let turn: usize = match crate::get_redis_client().await {
+        Ok(mut redis) => redis::cmd("INCR")
+            .arg("youtube_api_key_turn")
+            .query_async(&mut redis)
+            .await
+            .unwrap_or_default(),
+        Err(_) => 0,
+    };
+    for i in 0..api_keys.len() {
+        let api_key = &api_keys[(turn + i) % api_keys.len()];
+        if !is_quota_exhausted(api_key).await {
+            return Some(api_key.clone());
+        }
+    }
+    warn!("all the youtube API keys are out of quota, falling back to yt-dlp");
+    None
+}
+
+async fn is_quota_exhausted(api_key: &str) -> bool {
+    let Ok(mut redis) = crate::get_redis_client().await else {
+        return false;
+    };
+    let key_id = api_key_id(api_key);
+    let (day, _) = quota_day_and_seconds_to_reset();
+    let exceeded: Option<String> = redis::cmd("GET")
+        .arg(format!("youtube_quota_exceeded={key_id}"))
+        .query_async(&mut redis)
+        .await
+        .unwrap_or_default();
+    let used: u64 = redis::cmd("GET")
+        .arg(format!("youtube_quota_used={day}:{key_id}"))
+        .query_async::<Option<u64>>(&mut redis)
+        .await
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    let daily_quota: u64 = conf()
+        .get(ConfName::YoutubeApiDailyQuota)
+        .ok()
+        .and_then(|quota| quota.parse().ok())
+        .unwrap_or(10000);
+    debug!("youtube API key {} used {} quota units", key_id, used);
+    exceeded.is_some() || used >= daily_quota
+}
+
+/// counts the estimated quota spent by the request and marks the key as exhausted until the
+/// quota resets if youtube says so
+async fn with_quota<T>(
+    api_key: &str,
+    cost: u64,
+    request: impl std::future::Future<Output = google_youtube3::client::Result<T>>,
+) -> eyre::Result<T> {
+    let key_id = api_key_id(api_key);
+    let (day, seconds_to_reset) = quota_day_and_seconds_to_reset();
+    let mut redis = crate::get_redis_client().await.ok();
+    if let Some(ref mut redis) = redis {
+        let spent: redis::RedisResult<()> = redis::pipe()
+            .cmd("INCRBY")
+            .arg(format!("youtube_quota_used={day}:{key_id}"))
+            .arg(cost)
+            .ignore()
+            .cmd("EXPIRE")
+            .arg(format!("youtube_quota_used={day}:{key_id}"))
+            .arg(seconds_to_reset)
+            .ignore()
+            .query_async(redis)
+            .await;
+        if let Err(e) = spent {
+            warn!("could not track the youtube API quota: {}", e);
+        }
+    }
+
+    match request.await {
+        Err(google_youtube3::client::Error::BadRequest(error))
+            if error.to_string().contains("quotaExceeded") =>
+        {
+            warn!(
+                "youtube API quota exceeded for key {}, it will be skipped for {}s",
+                key_id, seconds_to_reset
+            );
+            if let Some(ref mut redis) = redis {
+                let marked: redis::RedisResult<()> = redis::cmd("SET")
+                    .arg(format!("youtube_quota_exceeded={key_id}"))
+                    .arg(day)
+                    .arg("EX")
+                    .arg(seconds_to_reset)
+                    .query_async(redis)
+                    .await;
+                if let Err(e) = marked {
+                    warn!("could not mark the youtube API key as exhausted: {}", e);
+                }
+            }
+            Err(QuotaExceeded(key_id).into())
+        }
+        result => Ok(result?),
+    }
+}
+
 #[io_cached(
     map_error = r##"|e| eyre::Error::new(e)"##,
     ty = "AsyncRedisCache<Url, Url>",
@@ -481,12 +994,13 @@ async fn get_youtube_stream_url(url: &Url) -> eyre::Result<Url> {
     debug!("getting stream_url for yt video: {}", url);
     let extra_args: Vec<String> =
         serde_json::from_str(conf().get(ConfName::YoutubeYtDlpExtraArgs)?.as_str()).map_err(|_| eyre!(r#"failed to parse YOUTUBE_YT_DLP_GET_URL_EXTRA_ARGS allowed syntax is ["arg1#", "arg2", "arg3", ...]"#))?;
+    let (video_url, audio_language) = split_audio_language(url);
     let mut command = tokio::process::Command::new("yt-dlp");
     command
         .arg("-f")
-        .arg("bestaudio")
+        .arg(audio_format_selector(audio_language.as_deref()))
         .arg("--get-url")
-        .arg(url.as_str());
+        .arg(video_url.as_str());
 
     for arg in extra_args {
         command.arg(arg);
@@ -502,7 +1016,7 @@ async fn get_youtube_stream_url(url: &Url) -> eyre::Result<Url> {
                 Err(e) => {
                     warn!(
                         "error while parsing stream url using yt-dlp:\nerror: {}\nyt-dlp stdout: {}\nyt-dlp stderr: {}",
-                        e.to_string(),
+                        e,
                         raw_url,
                         std::str::from_utf8(&x.stderr).unwrap_or_default()
                     );
@@ -514,6 +1028,23 @@ async fn get_youtube_stream_url(url: &Url) -> eyre::Result<Url> {
     }
 }
 
+/// googlevideo urls carry their expiration as a unix timestamp in the `expire` query param
+fn is_stream_url_expired(stream_url: &Url) -> bool {
+    const EXPIRATION_MARGIN_SECS: u64 = 300;
+    let Some(expire) = stream_url
+        .query_pairs()
+        .find(|(key, _)| key == "expire")
+        .and_then(|(_, value)| value.parse::<u64>().ok())
+    else {
+        return false;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    expire <= now + EXPIRATION_MARGIN_SECS
+}
+
 async fn feed_url_for_yt_playlist(url: &Url) -> eyre::Result<Url> {
     let playlist_id = url
         .query_pairs()
@@ -538,7 +1069,11 @@ async fn feed_url_for_yt_channel(url: &Url) -> eyre::Result<Url> {
         return Ok(url.to_owned());
     }
     let url_with_channel_id = find_yt_channel_url_with_c_id(url).await?;
-    let channel_id = url_with_channel_id.path_segments().unwrap().last().unwrap();
+    let channel_id = url_with_channel_id
+        .path_segments()
+        .unwrap()
+        .next_back()
+        .unwrap();
     let mut feed_url = Url::parse("https://www.youtube.com/feeds/videos.xml")?;
     feed_url
         .query_pairs_mut()
@@ -578,7 +1113,7 @@ async fn find_yt_channel_url_with_c_id(url: &Url) -> eyre::Result<Url> {
         Err(e) => {
             warn!(
                         "error while translating channel name using yt-dlp:\nerror: {}\nyt-dlp stdout: {}\nyt-dlp stderr: {}",
-                        e.to_string(),
+                        e,
                         conversion.unwrap_or_default(),
                         std::str::from_utf8(&output.stderr).unwrap_or_default()
                     );
@@ -588,6 +1123,411 @@ async fn find_yt_channel_url_with_c_id(url: &Url) -> eyre::Result<Url> {
     Ok(Url::parse(feed_url)?)
 }
 
+/// search urls (es: https://www.youtube.com/results?search_query=rust) and hashtag urls
+/// (es: https://www.youtube.com/hashtag/rust) are both turned into a search
+fn parse_search_query(url: &Url) -> Option<String> {
+    let mut segments = url.path_segments()?.filter(|s| !s.is_empty());
+    match segments.next()? {
+        "results" => url
+            .query_pairs()
+            .find(|(key, _)| key == "search_query")
+            .map(|(_, value)| value.into_owned()),
+        "hashtag" => segments.next().map(|hashtag| format!("#{hashtag}")),
+        _ => None,
+    }
+}
+
+async fn generate_search_feed(
+    search_url: &Url,
+    search_query: &str,
+    api_key: Option<String>,
+) -> eyre::Result<String> {
+    info!(
+        "starting youtube feed generation for search {}",
+        search_query
+    );
+    let max_results: usize = conf().get(ConfName::YoutubeSearchMaxResults)?.parse()?;
+    let published_after_days: i64 = conf()
+        .get(ConfName::YoutubeSearchPublishedAfterDays)?
+        .parse()?;
+    let published_after = chrono::Utc::now() - chrono::Duration::days(published_after_days);
+
+    let mut feed_builder = match api_key {
+        Some(api_key) => {
+            let items =
+                fetch_search_items(search_query, published_after, &api_key, max_results).await?;
+            let duration_map = create_duration_url_map(&items, &api_key).await?;
+            let mut feed_builder = provider::build_default_rss_structure();
+            feed_builder.items(build_channel_items_from_playlist(items, duration_map));
+            feed_builder
+        }
+        None => {
+            let mut playlist = if search_query.starts_with('#') {
+                fetch_ytdlp_playlist(search_url.as_str(), max_results).await?
+            } else {
+                fetch_ytdlp_playlist(
+                    &format!("ytsearchdate{max_results}:{search_query}"),
+                    max_results,
+                )
+                .await?
+            };
+            let published_after = published_after.timestamp();
+            let entries = std::mem::take(&mut playlist.entries)
+                .into_iter()
+                .filter(|entry| entry.timestamp.is_none_or(|t| t >= published_after))
+                .collect();
+            let mut feed_builder = build_ytdlp_channel(&playlist);
+            feed_builder.items(convert_ytdlp_entries_to_items(entries).await);
+            feed_builder
+        }
+    };
+
+    feed_builder
+        .title(format!("YouTube search: {search_query}"))
+        .description(format!("newest YouTube videos for {search_query}"))
+        .link(search_url.to_string());
+    Ok(feed_builder.build().to_string())
+}
+
+/// the search results are converted to playlist items, so that they can be handled like the
+/// videos of a channel
+async fn fetch_search_items(
+    search_query: &str,
+    published_after: chrono::DateTime<chrono::Utc>,
+    api_key: &str,
+    max_results: usize,
+) -> eyre::Result<Vec<PlaylistItem>> {
+    let hub = get_youtube_hub();
+    let mut items: Vec<PlaylistItem> = Vec::with_capacity(max_results);
+    let mut next_page_token: Option<String> = None;
+    loop {
+        let mut search_request = hub
+            .search()
+            .list(&vec!["snippet".into()])
+            .q(search_query)
+            .add_type("video")
+            .order("date")
+            .published_after(published_after)
+            .max_results((max_results - items.len()).min(50).try_into()?)
+            .param("key", api_key);
+        if let Some(ref next_page_token) = next_page_token {
+            search_request = search_request.page_token(next_page_token);
+        }
+
+        let response = with_quota(api_key, SEARCH_QUOTA_COST, search_request.doit()).await?;
+        items.extend(
+            response
+                .1
+                .items
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|result| {
+                    let snippet = result.snippet?;
+                    Some(PlaylistItem {
+                        snippet: Some(PlaylistItemSnippet {
+                            resource_id: result.id,
+                            title: snippet.title,
+                            description: snippet.description,
+                            published_at: snippet.published_at,
+                            thumbnails: snippet.thumbnails,
+                            channel_title: snippet.channel_title,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    })
+                }),
+        );
+        next_page_token = response.1.next_page_token;
+        if next_page_token.is_none() || items.len() >= max_results {
+            break;
+        }
+    }
+    info!("found {} videos for search {}", items.len(), search_query);
+    Ok(items)
+}
+
+/// the atom feed only has the last 15 videos, yt-dlp can list up to YOUTUBE_MAX_RESULTS videos
+async fn generate_ytdlp_feed(channel_url: &Url) -> eyre::Result<String> {
+    info!(
+        "starting youtube feed generation for {} using yt-dlp",
+        channel_url
+    );
+    let path = channel_url.path();
+    let is_channel = ["/channel/", "/user/", "/c/", "/@"]
+        .iter()
+        .any(|prefix| path.starts_with(prefix));
+    let list_url = if path.starts_with("/playlist") {
+        channel_url.clone()
+    } else if is_channel {
+        // the uploads playlist has all the videos, the channel page would list its tabs
+        let url = find_yt_channel_url_with_c_id(channel_url).await?;
+        let channel_id = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .ok_or_else(|| eyre!("no channel id in {}", url))?;
+        let mut uploads_url = Url::parse("https://www.youtube.com/playlist")?;
+        uploads_url.query_pairs_mut().append_pair(
+            "list",
+            &format!("UU{}", channel_id.strip_prefix("UC").unwrap_or(channel_id)),
+        );
+        uploads_url
+    } else {
+        return Err(eyre!("yt-dlp listing is not supported for {}", channel_url));
+    };
+
+    let max_fetched_items: usize = conf().get(ConfName::YoutubeMaxResults)?.parse()?;
+    let mut playlist = fetch_ytdlp_playlist(list_url.as_str(), max_fetched_items).await?;
+    if is_channel {
+        // the uploads playlist is named "Uploads from <channel>"
+        playlist.title = playlist.channel.take().or(playlist.title);
+        playlist.webpage_url = Some(channel_url.to_string());
+    }
+    Ok(convert_ytdlp_playlist_to_rss(playlist).await)
+}
+
+/// the podcasts tab lists playlists, the feed is made of the videos of all of them
+async fn generate_podcasts_tab_feed(
+    channel_url: &Url,
+    api_key: Option<&str>,
+) -> eyre::Result<String> {
+    info!(
+        "starting youtube feed generation for podcasts of {}",
+        channel_url
+    );
+    let max_fetched_items: usize = conf().get(ConfName::YoutubeMaxResults)?.parse()?;
+    let mut podcasts = fetch_ytdlp_playlist(channel_url.as_str(), max_fetched_items).await?;
+    let playlist_urls: Vec<Url> = std::mem::take(&mut podcasts.entries)
+        .into_iter()
+        .filter_map(|entry| entry.url?.parse().ok())
+        .collect();
+    debug!("found podcast playlists {:?}", playlist_urls);
+
+    let mut items = Vec::new();
+    for playlist_url in playlist_urls {
+        match api_key {
+            Some(api_key) => {
+                let Some((_, playlist_id)) =
+                    playlist_url.query_pairs().find(|(key, _)| key == "list")
+                else {
+                    continue;
+                };
+                let (_, playlist_items) =
+                    fetch_from_api(IdType::Playlist(playlist_id.into()), api_key.to_string())
+                        .await?;
+                items.extend(playlist_items);
+            }
+            None => {
+                let playlist =
+                    fetch_ytdlp_playlist(playlist_url.as_str(), max_fetched_items).await?;
+                items.extend(convert_ytdlp_entries_to_items(playlist.entries).await);
+            }
+        }
+    }
+    // newest first, dates are in rfc2822 so they need to be parsed to be compared
+    items.sort_by_key(|item| {
+        std::cmp::Reverse(
+            item.pub_date()
+                .and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok()),
+        )
+    });
+    items.truncate(max_fetched_items);
+
+    let mut feed_builder = build_ytdlp_channel(&podcasts);
+    feed_builder.items(items);
+    Ok(feed_builder.build().to_string())
+}
+
+#[derive(Deserialize, Debug)]
+struct YtDlpPlaylist {
+    title: Option<String>,
+    channel: Option<String>,
+    description: Option<String>,
+    webpage_url: Option<String>,
+    #[serde(default)]
+    thumbnails: Vec<YtDlpThumbnail>,
+    #[serde(default)]
+    entries: Vec<YtDlpEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct YtDlpEntry {
+    id: String,
+    url: Option<String>,
+    title: Option<String>,
+    description: Option<String>,
+    duration: Option<f64>,
+    timestamp: Option<i64>,
+    channel: Option<String>,
+    live_status: Option<String>,
+    #[serde(default)]
+    thumbnails: Vec<YtDlpThumbnail>,
+}
+
+#[derive(Deserialize, Debug)]
+struct YtDlpThumbnail {
+    url: String,
+    id: Option<String>,
+}
+
+/// lists the videos of a playlist or of a channel tab without fetching each video page
+async fn fetch_ytdlp_playlist(url: &str, max_items: usize) -> eyre::Result<YtDlpPlaylist> {
+    debug!("listing {} with yt-dlp", url);
+    let output = Command::new("yt-dlp")
+        .arg("--flat-playlist")
+        .arg("-J")
+        // without this flat playlists have no upload date
+        .args(["--extractor-args", "youtubetab:approximate_date"])
+        .args(["--playlist-end", max_items.to_string().as_str()])
+        .arg(url)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "yt-dlp could not list {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+fn build_ytdlp_channel(playlist: &YtDlpPlaylist) -> ChannelBuilder {
+    let mut feed_builder = provider::build_default_rss_structure();
+    feed_builder.title(playlist.title.clone().unwrap_or_default());
+    feed_builder.description(playlist.description.clone().unwrap_or_default());
+    feed_builder.link(playlist.webpage_url.clone().unwrap_or_default());
+    let image = playlist
+        .thumbnails
+        .iter()
+        .find(|t| t.id.as_deref() == Some("avatar_uncropped"))
+        .or(playlist.thumbnails.last())
+        .map(|t| t.url.clone());
+    let mut image_builder = ImageBuilder::default();
+    image_builder.url(image.clone().unwrap_or_default());
+    feed_builder.image(Some(image_builder.build()));
+    let mut itunes_ext_builder = ITunesChannelExtensionBuilder::default();
+    itunes_ext_builder.image(image);
+    feed_builder.itunes_ext(Some(itunes_ext_builder.build()));
+    feed_builder
+}
+
+async fn convert_ytdlp_playlist_to_rss(mut playlist: YtDlpPlaylist) -> String {
+    let entries = std::mem::take(&mut playlist.entries);
+    let mut feed_builder = build_ytdlp_channel(&playlist);
+    feed_builder.items(convert_ytdlp_entries_to_items(entries).await);
+    feed_builder.build().to_string()
+}
+
+async fn convert_ytdlp_entries_to_items(entries: Vec<YtDlpEntry>) -> Vec<Item> {
+    let entries: Vec<YtDlpEntry> = entries
+        .into_iter()
+        .filter(|entry| {
+            let live_status = entry
+                .live_status
+                .as_deref()
+                .map_or(LiveStatus::NotLive, LiveStatus::from_ytdlp);
+            if live_status != LiveStatus::NotLive {
+                info!("skipping {}, {}", entry.id, NotAVod(live_status));
+            }
+            live_status == LiveStatus::NotLive
+        })
+        .collect();
+    // shorts are listed without duration
+    let missing_durations = entries
+        .iter()
+        .filter(|entry| entry.duration.is_none())
+        .filter_map(|entry| youtube_video_url(&entry.id).parse::<Url>().ok())
+        .collect();
+    let duration_map = lookup_durations_with_ytdlp(missing_durations).await;
+
+    let mut items = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let link = youtube_video_url(&entry.id);
+        let duration = match entry.duration {
+            Some(duration) => Some(duration as usize),
+            None => match duration_map.get(&link) {
+                Some(duration) => *duration,
+                None => continue, // live or upcoming
+            },
+        };
+        let description = entry.description.unwrap_or_default();
+        let mut item_builder = ItemBuilder::default();
+        item_builder.title(entry.title);
+        item_builder.description(Some(description.clone()));
+        item_builder.link(Some(link.clone()));
+        item_builder.guid(Some(GuidBuilder::default().value(link).build()));
+        item_builder.author(entry.channel);
+        item_builder.pub_date(
+            entry
+                .timestamp
+                .and_then(|timestamp| chrono::DateTime::from_timestamp(timestamp, 0))
+                .map(|date| date.to_rfc2822()),
+        );
+        let itunes_item_extension = ITunesItemExtensionBuilder::default()
+            .summary(Some(description))
+            .duration(duration.map(|d| format!("{:02}:{:02}:{:02}", d / 3600, d / 60 % 60, d % 60)))
+            .image(entry.thumbnails.last().map(|t| t.url.clone()))
+            .build();
+        item_builder.itunes_ext(Some(itunes_item_extension));
+        items.push(item_builder.build());
+    }
+    items
+}
+
+fn youtube_video_url(video_id: &str) -> String {
+    format!("https://www.youtube.com/watch?v={video_id}")
+}
+
+/// looks up the durations concurrently, a failed or timed out lookup gives no duration
+/// instead of failing the whole feed, live and upcoming videos are left out of the map
+async fn lookup_durations_with_ytdlp(urls: Vec<Url>) -> HashMap<String, Option<usize>> {
+    let parallelism: usize = conf()
+        .get(ConfName::YoutubeDurationLookupParallelism)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(4)
+        .max(1);
+    let timeout = Duration::from_secs(
+        conf()
+            .get(ConfName::YoutubeDurationLookupTimeoutSeconds)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(30),
+    );
+    debug!(
+        "looking up {} durations, {} at a time",
+        urls.len(),
+        parallelism
+    );
+
+    futures::stream::iter(urls)
+        .map(|url| async move {
+            let duration =
+                match tokio::time::timeout(timeout, get_youtube_video_duration_with_ytdlp(&url))
+                    .await
+                {
+                    Ok(Ok(duration)) => Some(duration),
+                    Ok(Err(e)) if e.is::<NotAVod>() => {
+                        info!("skipping {}, {}", url, e);
+                        return None;
+                    }
+                    Ok(Err(e)) => {
+                        warn!("could not get duration of {}: {}", url, e);
+                        None
+                    }
+                    Err(_) => {
+                        warn!("duration lookup of {} timed out after {:?}", url, timeout);
+                        None
+                    }
+                };
+            Some((url.to_string(), duration))
+        })
+        .buffer_unordered(parallelism)
+        .filter_map(futures::future::ready)
+        .collect()
+        .await
+}
+
 fn convert_atom_to_rss(feed: Feed, duration_map: HashMap<String, Option<usize>>) -> String {
     let mut feed_builder = provider::build_default_rss_structure();
     feed_builder.description(feed.description.map(|d| d.content).unwrap_or_default());
@@ -609,6 +1549,13 @@ fn convert_atom_to_rss(feed: Feed, duration_map: HashMap<String, Option<usize>>)
     let items = feed
         .entries
         .into_iter()
+        .filter(|entry| {
+            // live and upcoming videos are not in the map
+            entry
+                .links
+                .first()
+                .is_some_and(|link| duration_map.contains_key(&link.href))
+        })
         .map(|entry| {
             let mut item_builder = ItemBuilder::default();
             item_builder.title(entry.title.map(|d| d.content));
@@ -647,9 +1594,9 @@ fn convert_atom_to_rss(feed: Feed, duration_map: HashMap<String, Option<usize>>)
     not(test),
     io_cached(
         map_error = r##"|e| eyre::Error::new(e)"##,
-        ty = "AsyncRedisCache<Url, Option<usize>>",
+        ty = "AsyncRedisCache<Url, usize>",
         create = r##" {
-        AsyncRedisCache::new("cached_yt_video_duration=", std::time::Duration::from_secs(86400))
+        AsyncRedisCache::new("cached_yt_video_duration_v2=", std::time::Duration::from_secs(86400))
             .set_refresh(false)
             .set_connection_string(&conf().get(ConfName::RedisUrl).unwrap())
             .build()
@@ -658,27 +1605,45 @@ fn convert_atom_to_rss(feed: Feed, duration_map: HashMap<String, Option<usize>>)
 } "##
     )
 )]
-async fn get_youtube_video_duration_with_ytdlp(url: &Url) -> eyre::Result<Option<usize>> {
+async fn get_youtube_video_duration_with_ytdlp(url: &Url) -> eyre::Result<usize> {
     debug!("getting duration for yt video: {}", url);
 
+    // without --ignore-no-formats-error yt-dlp fails on upcoming videos
     let output = Command::new("yt-dlp")
-        .arg("--get-duration")
+        .arg("--ignore-no-formats-error")
+        .arg("-O")
+        .arg("%(live_status)s %(duration_string)s")
         .arg(url.to_string())
+        .kill_on_drop(true)
         .output()
-        .await;
-    if let Ok(x) = output {
-        let duration_str = std::str::from_utf8(&x.stdout).unwrap().trim().to_string();
-        Ok(Some(
-            parse_duration(&duration_str)
-                .unwrap_or_default()
-                .as_secs()
-                .try_into()
-                .unwrap(),
-        ))
-    } else {
-        warn!("could not parse youtube video duration");
-        Ok(Some(0))
+        .await?;
+    let stdout = std::str::from_utf8(&output.stdout).unwrap_or_default();
+    let (live_status, duration) = parse_live_status_and_duration(stdout);
+    if live_status != LiveStatus::NotLive {
+        // not cached, the video has to be checked again until it is a VOD
+        return Err(NotAVod(live_status).into());
     }
+    // not cached either, a failure is often temporary (es: rate limiting)
+    duration.ok_or_else(|| {
+        eyre!(
+            "could not parse youtube video duration: {}",
+            std::str::from_utf8(&output.stderr).unwrap_or_default()
+        )
+    })
+}
+
+/// parses the "<live_status> <duration_string>" printed by yt-dlp, es: "not_live 1:02:03"
+fn parse_live_status_and_duration(output: &str) -> (LiveStatus, Option<usize>) {
+    let mut fields = output.split_whitespace();
+    let live_status = fields
+        .next()
+        .map_or(LiveStatus::NotLive, LiveStatus::from_ytdlp);
+    let duration = fields
+        .next()
+        .and_then(|duration| parse_duration(duration).ok())
+        .and_then(|duration| usize::try_from(duration.as_secs()).ok())
+        .filter(|duration| *duration > 0);
+    (live_status, duration)
 }
 
 fn parse_duration(duration_str: &str) -> Result<Duration, String> {
@@ -707,6 +1672,145 @@ mod tests {
     use super::*;
     use test_log::test;
 
+    #[test]
+    fn test_parse_search_query() {
+        let url = Url::parse("https://www.youtube.com/results?search_query=rust+language").unwrap();
+        assert_eq!(parse_search_query(&url).as_deref(), Some("rust language"));
+        let url = Url::parse("https://www.youtube.com/hashtag/rustlang").unwrap();
+        assert_eq!(parse_search_query(&url).as_deref(), Some("#rustlang"));
+        let url = Url::parse("https://www.youtube.com/@channel").unwrap();
+        assert_eq!(parse_search_query(&url), None);
+    }
+
+    #[test]
+    fn test_parse_live_status_and_duration() {
+        assert_eq!(
+            parse_live_status_and_duration("not_live 1:02:03\n"),
+            (LiveStatus::NotLive, Some(3723))
+        );
+        assert_eq!(
+            parse_live_status_and_duration("was_live 45"),
+            (LiveStatus::NotLive, Some(45))
+        );
+        assert_eq!(
+            parse_live_status_and_duration("is_live NA"),
+            (LiveStatus::Live, None)
+        );
+        assert_eq!(
+            parse_live_status_and_duration("is_upcoming NA"),
+            (LiveStatus::Upcoming, None)
+        );
+        assert_eq!(
+            parse_live_status_and_duration(""),
+            (LiveStatus::NotLive, None)
+        );
+    }
+
+    #[test]
+    fn test_parse_video_id() {
+        for url in [
+            "https://www.youtube.com/watch?v=UMO52N2vfk0",
+            "https://www.youtube.com/watch?v=UMO52N2vfk0&list=PLlTLHnxSVuIyeEZPBIQF_krewJkY2JSwi",
+            "https://youtu.be/UMO52N2vfk0",
+            "https://www.youtube.com/shorts/UMO52N2vfk0",
+            "https://www.youtube.com/live/UMO52N2vfk0",
+        ] {
+            let url = Url::parse(url).unwrap();
+            assert_eq!(parse_video_id(&url).as_deref(), Some("UMO52N2vfk0"));
+        }
+        for url in [
+            "https://www.youtube.com/@channel",
+            "https://www.youtube.com/playlist?list=PLlTLHnxSVuIyeEZPBIQF_krewJkY2JSwi",
+            "https://www.youtube.com/watch",
+        ] {
+            assert_eq!(parse_video_id(&Url::parse(url).unwrap()), None);
+        }
+    }
+
+    #[test]
+    fn test_audio_language() {
+        let feed_url = Url::parse("https://www.youtube.com/@channel?audio_lang=it").unwrap();
+        let audio_language = parse_audio_language(&feed_url).unwrap();
+        let mut item = ItemBuilder::default()
+            .link(Some(youtube_video_url("UMO52N2vfk0")))
+            .build();
+        set_audio_language(&mut item, &audio_language);
+        assert_eq!(item.link(), Some(youtube_video_url("UMO52N2vfk0").as_str()));
+
+        let media_url = item.extensions()["vod2pod"]["media_url"][0]
+            .value()
+            .unwrap();
+        let (video_url, audio_language) = split_audio_language(&media_url.parse().unwrap());
+        assert_eq!(video_url.as_str(), youtube_video_url("UMO52N2vfk0"));
+        assert_eq!(
+            audio_format_selector(audio_language.as_deref()),
+            "bestaudio[language^=it]/bestaudio[format_note*=original]/bestaudio"
+        );
+
+        for url in [
+            "https://www.youtube.com/@channel",
+            "https://www.youtube.com/@channel?audio_lang=original",
+            "https://www.youtube.com/@channel?audio_lang=]/worst",
+        ] {
+            assert_eq!(parse_audio_language(&Url::parse(url).unwrap()), None);
+        }
+        assert_eq!(
+            audio_format_selector(None),
+            "bestaudio[format_note*=original]/bestaudio"
+        );
+    }
+
+    #[test]
+    fn test_quota_helpers() {
+        assert_eq!(api_key_id("AIzaSyBTCCEOHm"), "CCEOHm");
+        assert_eq!(api_key_id("abc"), "abc");
+        let (day, seconds_to_reset) = quota_day_and_seconds_to_reset();
+        assert!(chrono::NaiveDate::parse_from_str(&day, "%Y-%m-%d").is_ok());
+        assert!((1..=24 * 3600).contains(&seconds_to_reset));
+    }
+
+    #[test]
+    fn test_parse_channel_tab() {
+        let url = Url::parse("https://www.youtube.com/@channel/streams").unwrap();
+        let (channel_url, tab) = parse_channel_tab(&url);
+        assert_eq!(channel_url.as_str(), "https://www.youtube.com/@channel");
+        assert_eq!(tab, ChannelTab::Streams);
+
+        let url = Url::parse("https://www.youtube.com/channel/UCxyz/shorts").unwrap();
+        let (channel_url, tab) = parse_channel_tab(&url);
+        assert_eq!(
+            channel_url.as_str(),
+            "https://www.youtube.com/channel/UCxyz"
+        );
+        assert_eq!(tab, ChannelTab::Shorts);
+
+        let url = Url::parse("https://www.youtube.com/@channel").unwrap();
+        assert_eq!(parse_channel_tab(&url), (url.clone(), ChannelTab::All));
+        let url = Url::parse("https://www.youtube.com/playlist?list=PL123").unwrap();
+        assert_eq!(parse_channel_tab(&url), (url.clone(), ChannelTab::All));
+    }
+
+    #[test]
+    fn test_stream_url_expiration() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let url_expiring_at = |expire: u64| {
+            Url::parse(&format!(
+                "https://rr1---sn-abc.googlevideo.com/videoplayback?expire={expire}&ei=abc"
+            ))
+            .unwrap()
+        };
+
+        assert!(is_stream_url_expired(&url_expiring_at(now - 10)));
+        assert!(is_stream_url_expired(&url_expiring_at(now + 60)));
+        assert!(!is_stream_url_expired(&url_expiring_at(now + 3600)));
+        assert!(!is_stream_url_expired(
+            &Url::parse("https://rr1---sn-abc.googlevideo.com/videoplayback").unwrap()
+        ));
+    }
+
     #[tokio::test]
     async fn test_build_items_for_playlist_requires_api_key() {
         let id = "UUXuqSBlHAE6Xw-yeJA0Tunw".to_string();
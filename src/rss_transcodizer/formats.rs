@@ -0,0 +1,236 @@
+use std::str::FromStr;
+
+use atom_syndication::{ContentBuilder, EntryBuilder, FeedBuilder, LinkBuilder, PersonBuilder};
+use chrono::{DateTime, FixedOffset, Utc};
+use rss::{Channel, Item};
+use serde_json::json;
+
+/// the transcodized feed is always generated (and cached) as rss, the other formats are
+/// rendered from it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FeedFormat {
+    Rss,
+    /// https://www.jsonfeed.org/version/1.1/
+    Json,
+    Atom,
+}
+
+impl FromStr for FeedFormat {
+    type Err = eyre::Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "rss" => Ok(FeedFormat::Rss),
+            "json" => Ok(FeedFormat::Json),
+            "atom" => Ok(FeedFormat::Atom),
+            _ => Err(eyre::eyre!(
+                "unsupported format {format}, use rss, json or atom"
+            )),
+        }
+    }
+}
+
+impl FeedFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            FeedFormat::Rss => "application/xml",
+            FeedFormat::Json => "application/feed+json",
+            FeedFormat::Atom => "application/atom+xml",
+        }
+    }
+}
+
+pub fn render(rss_body: String, format: FeedFormat) -> eyre::Result<String> {
+    if format == FeedFormat::Rss {
+        return Ok(rss_body);
+    }
+    let channel = Channel::read_from(rss_body.as_bytes())?;
+    match format {
+        FeedFormat::Rss => Ok(rss_body),
+        FeedFormat::Json => Ok(render_json_feed(&channel).to_string()),
+        FeedFormat::Atom => Ok(render_atom(&channel).to_string()),
+    }
+}
+
+fn pub_date(item: &Item) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc2822(item.pub_date()?).ok()
+}
+
+fn item_image(item: &Item) -> Option<&str> {
+    item.itunes_ext().and_then(|itunes| itunes.image())
+}
+
+fn channel_image(channel: &Channel) -> Option<&str> {
+    channel
+        .image()
+        .map(|image| image.url())
+        .or_else(|| channel.itunes_ext().and_then(|itunes| itunes.image()))
+        .filter(|image| !image.is_empty())
+}
+
+fn duration_secs(item: &Item) -> Option<u64> {
+    let duration = item.itunes_ext()?.duration()?;
+    duration
+        .split(':')
+        .try_fold(0, |secs, part| Some(secs * 60 + part.parse::<u64>().ok()?))
+}
+
+fn render_json_feed(channel: &Channel) -> serde_json::Value {
+    let items: Vec<serde_json::Value> = channel
+        .items()
+        .iter()
+        .map(|item| {
+            let mut json_item = json!({
+                "id": item.guid().map(|guid| guid.value()).or(item.link()).unwrap_or_default(),
+                "url": item.link(),
+                "title": item.title(),
+                "content_html": item.description(),
+                "image": item_image(item),
+                "date_published": pub_date(item).map(|date| date.to_rfc3339()),
+                "authors": item.author().map(|author| vec![json!({ "name": author })]),
+            });
+            if let Some(enclosure) = item.enclosure() {
+                json_item["attachments"] = json!([{
+                    "url": enclosure.url(),
+                    "mime_type": enclosure.mime_type(),
+                    "size_in_bytes": enclosure.length().parse::<u64>().ok(),
+                    "duration_in_seconds": duration_secs(item),
+                }]);
+            }
+            without_nulls(json_item)
+        })
+        .collect();
+
+    without_nulls(json!({
+        "version": "https://jsonfeed.org/version/1.1",
+        "title": channel.title(),
+        "home_page_url": channel.link(),
+        "description": channel.description(),
+        "icon": channel_image(channel),
+        "language": channel.language(),
+        "items": items,
+    }))
+}
+
+/// optional fields are left out instead of being null
+fn without_nulls(mut value: serde_json::Value) -> serde_json::Value {
+    if let Some(object) = value.as_object_mut() {
+        object.retain(|_, value| !value.is_null());
+        for value in object.values_mut() {
+            if let Some(array) = value.as_array_mut() {
+                for element in array {
+                    *element = without_nulls(element.take());
+                }
+            }
+        }
+    }
+    value
+}
+
+fn render_atom(channel: &Channel) -> atom_syndication::Feed {
+    let entries: Vec<atom_syndication::Entry> = channel
+        .items()
+        .iter()
+        .map(|item| {
+            let mut links = Vec::new();
+            if let Some(link) = item.link() {
+                links.push(LinkBuilder::default().href(link).rel("alternate").build());
+            }
+            if let Some(enclosure) = item.enclosure() {
+                links.push(
+                    LinkBuilder::default()
+                        .href(enclosure.url())
+                        .rel("enclosure")
+                        .mime_type(Some(enclosure.mime_type().to_string()))
+                        .length(Some(enclosure.length().to_string()))
+                        .build(),
+                );
+            }
+            let date = pub_date(item).unwrap_or_default();
+            EntryBuilder::default()
+                .id(item
+                    .guid()
+                    .map(|guid| guid.value())
+                    .or(item.link())
+                    .unwrap_or_default())
+                .title(item.title().unwrap_or_default())
+                .updated(date)
+                .published(Some(date))
+                .links(links)
+                .authors(
+                    item.author()
+                        .map(|author| vec![PersonBuilder::default().name(author).build()])
+                        .unwrap_or_default(),
+                )
+                .content(item.description().map(|description| {
+                    ContentBuilder::default()
+                        .value(Some(description.to_string()))
+                        .content_type(Some("html".to_string()))
+                        .build()
+                }))
+                .build()
+        })
+        .collect();
+    let updated = entries
+        .iter()
+        .map(|entry| entry.updated)
+        .max()
+        .unwrap_or_else(|| Utc::now().fixed_offset());
+
+    FeedBuilder::default()
+        .id(channel.link())
+        .title(channel.title())
+        .subtitle(Some(atom_syndication::Text::plain(channel.description())))
+        .updated(updated)
+        .links(vec![LinkBuilder::default()
+            .href(channel.link())
+            .rel("alternate")
+            .build()])
+        .logo(channel_image(channel).map(str::to_string))
+        .entries(entries)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FEED: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd"><channel><title>podcast</title><link>https://example.com</link><description>a podcast</description>
+<item><title>episode</title><link>https://example.com/episode-1</link><guid>episode-1</guid><pubDate>Sun, 14 May 2023 10:00:00 +0000</pubDate><enclosure url="http://localhost/transcode_media/to.mp3?url=x" type="audio/mpeg" length="1000"/><itunes:duration>01:02:03</itunes:duration></item>
+</channel></rss>"#;
+
+    #[test]
+    fn test_render_json_feed() {
+        let json: serde_json::Value =
+            serde_json::from_str(&render(FEED.to_string(), FeedFormat::Json).unwrap()).unwrap();
+        assert_eq!(json["title"], "podcast");
+        let item = &json["items"][0];
+        assert_eq!(item["id"], "episode-1");
+        assert_eq!(item["date_published"], "2023-05-14T10:00:00+00:00");
+        assert_eq!(
+            item["attachments"][0]["url"],
+            "http://localhost/transcode_media/to.mp3?url=x"
+        );
+        assert_eq!(item["attachments"][0]["duration_in_seconds"], 3723);
+        assert!(item.get("image").is_none());
+    }
+
+    #[test]
+    fn test_render_atom() {
+        let atom = render(FEED.to_string(), FeedFormat::Atom).unwrap();
+        let feed = atom_syndication::Feed::read_from(atom.as_bytes()).unwrap();
+        assert_eq!(feed.title().as_str(), "podcast");
+        let entry = &feed.entries()[0];
+        assert_eq!(entry.id(), "episode-1");
+        let enclosure = entry
+            .links()
+            .iter()
+            .find(|link| link.rel() == "enclosure")
+            .unwrap();
+        assert_eq!(
+            enclosure.href(),
+            "http://localhost/transcode_media/to.mp3?url=x"
+        );
+    }
+}
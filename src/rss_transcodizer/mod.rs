@@ -1,16 +1,31 @@
+mod formats;
+
 use std::collections::BTreeMap;
+use std::future::Future;
 use std::time::Duration;
 
+#[allow(unused_imports)]
+use cached::proc_macro::io_cached;
+#[allow(unused_imports)]
+use cached::AsyncRedisCache;
+use chrono::DateTime;
 use eyre::eyre;
-use log::debug;
+use futures::StreamExt;
+use log::{debug, warn};
+use regex::Regex;
 use reqwest::Url;
 use rss::extension::itunes::ITunesCategory;
 use rss::Channel;
 use rss::{Enclosure, Item};
+use tokio::process::Command;
 
 use crate::configs::{conf, AudioCodec, Conf, ConfName};
+use crate::provider::{self, MediaProvider};
+use crate::transcoder::{self, Chapter};
+
+pub use self::formats::{render, FeedFormat};
 
-pub fn inject_vod2pod_customizations(
+pub async fn inject_vod2pod_customizations(
     rss_body: String,
     transcode_service_url: Option<Url>,
 ) -> eyre::Result<String> {
@@ -34,12 +49,24 @@ pub fn inject_vod2pod_customizations(
         "content".to_string(),
         "http://purl.org/rss/1.0/modules/content/".to_string(),
     );
+    namespaces.insert(
+        "podcast".to_string(),
+        "https://podcastindex.org/namespace/1.0".to_string(),
+    );
     injected_feed.set_namespaces(namespaces);
     injected_feed.set_language("en-US".to_string());
+    if transcode_service_url.is_some() {
+        fill_missing_durations(injected_feed.items_mut()).await;
+    }
+    let channel_title = injected_feed.title().to_string();
     injected_feed
         .items_mut()
         .iter_mut()
         .try_for_each(|item| -> eyre::Result<_> {
+            let chapters = parse_chapters(item.description().unwrap_or_default());
+            // read before the vod2pod extensions are removed
+            let media_url = media_url(item);
+            let cut = take_cut_ranges(item);
             let description = get_description(item);
             item.set_description(description);
             let bitrate: u64 = conf()
@@ -51,19 +78,42 @@ pub fn inject_vod2pod_customizations(
             let codec: AudioCodec = conf().get(ConfName::AudioCodec).unwrap().into();
             let ext = format!(".{}", codec.get_extension_str());
             if let Some(mut transcode_service_url) = transcode_service_url.clone() {
-                let duration_secs = &item
-                    .itunes_ext()
-                    .and_then(|i| Some(parse_duration(i.duration()?).ok()?.as_secs()))
-                    .ok_or(eyre!(
-                        "no duration found or could not parse {:?}",
-                        item.itunes_ext()
-                    ))?;
+                // the items that can't be transcoded are kept as they are
+                let Some(media_url) = media_url else {
+                    warn!(
+                        "no url found in item {:?}, it won't be transcoded",
+                        item.title()
+                    );
+                    return Ok(());
+                };
+                let Some(ref duration_secs) = item_duration(item) else {
+                    warn!(
+                        "no duration found for {}, it won't be transcoded",
+                        media_url
+                    );
+                    return Ok(());
+                };
                 transcode_service_url
                     .query_pairs_mut()
                     .append_pair("bitrate", bitrate.to_string().as_str())
                     .append_pair("uuid", generation_uuid.as_str())
                     .append_pair("duration", duration_secs.to_string().as_str())
-                    .append_pair("url", item.link().ok_or(eyre!("not url found in item"))?)
+                    .append_pair("url", media_url.as_str());
+                if let Some(ref cut) = cut {
+                    transcode_service_url
+                        .query_pairs_mut()
+                        .append_pair("cut", cut);
+                }
+                if matches!(codec, AudioCodec::MP3) {
+                    append_id3_metadata(
+                        &mut transcode_service_url,
+                        item,
+                        &channel_title,
+                        &chapters,
+                    )?;
+                }
+                transcode_service_url
+                    .query_pairs_mut()
                     .append_pair("ext", ext.as_str()); //this should allways be last, some players refuse to play urls not ending in .mp3
 
                 let enclosure = Enclosure {
@@ -81,10 +131,295 @@ pub fn inject_vod2pod_customizations(
             Ok(())
         })?;
 
+    if let Some(transcode_service_url) = transcode_service_url {
+        inject_live_items(&mut injected_feed, transcode_service_url)?;
+    }
+
     Ok(injected_feed.to_string())
 }
 
+/// providers can pass the url to transcode in the `vod2pod:media_url` extension (es: a YouTube
+/// video with its audio language), podcast feeds already point to the audio file in the
+/// enclosure, their link is the web page of the episode
+fn media_url(item: &Item) -> Option<String> {
+    let media_url = item
+        .extensions()
+        .get("vod2pod")
+        .and_then(|extensions| extensions.get("media_url")?.first()?.value());
+    if let Some(media_url) = media_url {
+        return Some(media_url.to_string());
+    }
+    item.enclosure()
+        .map(|enclosure| enclosure.url().to_string())
+        .filter(|url| !url.is_empty())
+        .or_else(|| item.link().map(str::to_string))
+}
+
+fn item_duration(item: &Item) -> Option<u64> {
+    item.itunes_ext()
+        .and_then(|i| Some(parse_duration(i.duration()?).ok()?.as_secs()))
+}
+
+/// the duration is needed to transcode, when the feed does not have it the media is probed
+async fn fill_missing_durations(items: &mut [Item]) {
+    probe_missing_durations(items, probe_public_media).await
+}
+
+/// `probe` finds the duration of a media url
+async fn probe_missing_durations<F, Fut>(items: &mut [Item], probe: F)
+where
+    F: Fn(Url) -> Fut,
+    Fut: Future<Output = eyre::Result<usize>>,
+{
+    let conf_value = |name, default| {
+        conf()
+            .get(name)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(default)
+    };
+    let parallelism: usize = conf_value(ConfName::DurationProbeParallelism, 4).max(1);
+    let timeout = Duration::from_secs(conf_value(ConfName::DurationProbeTimeoutSeconds, 60) as u64);
+    let max_items = conf_value(ConfName::DurationProbeMaxItems, 20);
 
+    let missing_durations = items_to_probe(items, max_items);
+    if missing_durations.is_empty() {
+        return;
+    }
+    debug!(
+        "probing the duration of {} items, {} at a time",
+        missing_durations.len(),
+        parallelism
+    );
+
+    let probe = &probe;
+    let durations: Vec<(usize, Option<usize>)> = futures::stream::iter(missing_durations)
+        .map(|(i, media_url)| async move {
+            match tokio::time::timeout(timeout, probe(media_url.clone())).await {
+                Ok(Ok(duration)) => (i, Some(duration)),
+                Ok(Err(e)) => {
+                    warn!("could not find the duration of {}: {}", media_url, e);
+                    (i, None)
+                }
+                Err(_) => {
+                    warn!("probing the duration of {} timed out", media_url);
+                    (i, None)
+                }
+            }
+        })
+        .buffer_unordered(parallelism)
+        .collect()
+        .await;
+
+    for (i, duration) in durations {
+        let Some(duration) = duration else {
+            continue;
+        };
+        let mut itunes = items[i].itunes_ext().cloned().unwrap_or_default();
+        itunes.set_duration(format!(
+            "{:02}:{:02}:{:02}",
+            duration / 3600,
+            duration / 60 % 60,
+            duration % 60
+        ));
+        items[i].set_itunes_ext(Some(itunes));
+    }
+}
+
+/// the newest items without a duration, probing a whole back catalog would take minutes, the
+/// older items are left without a duration
+fn items_to_probe(items: &[Item], max_items: usize) -> Vec<(usize, Url)> {
+    let mut missing_durations: Vec<(usize, Url)> = items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| item_duration(item).is_none())
+        .filter_map(|(i, item)| Some((i, media_url(item)?.parse().ok()?)))
+        .collect();
+    // items without a date go last
+    missing_durations.sort_by_key(|(i, _)| {
+        std::cmp::Reverse(
+            items[*i]
+                .pub_date()
+                .and_then(|date| DateTime::parse_from_rfc2822(date).ok()),
+        )
+    });
+    if missing_durations.len() > max_items {
+        debug!(
+            "not probing the duration of {} older items",
+            missing_durations.len() - max_items
+        );
+        missing_durations.truncate(max_items);
+    }
+    missing_durations
+}
+
+/// the enclosures come from any feed, ffprobe and yt-dlp must not be pointed to the internal
+/// network
+async fn probe_public_media(media_url: Url) -> eyre::Result<usize> {
+    provider::resolve_public_addresses(&media_url).await?;
+    discover_duration(&media_url).await
+}
+
+/// ffprobe reads the duration from the media, if it can't yt-dlp may find it in the metadata of
+/// the page (es: the link of a video)
+#[cfg_attr(
+    not(test),
+    io_cached(
+        map_error = r##"|e| eyre::Error::new(e)"##,
+        ty = "AsyncRedisCache<Url, usize>",
+        create = r##" {
+        AsyncRedisCache::new("cached_media_duration=", std::time::Duration::from_secs(86400 * 30))
+            .set_refresh(false)
+            .set_connection_string(&conf().get(ConfName::RedisUrl).unwrap())
+            .build()
+            .await
+            .expect("media duration cache")
+} "##
+    )
+)]
+async fn discover_duration(media_url: &Url) -> eyre::Result<usize> {
+    let probed = match provider::from(media_url).get_stream_url(media_url).await {
+        Ok(stream_url) => transcoder::probe_duration(&stream_url).await,
+        Err(e) => Err(e),
+    };
+    match probed {
+        Ok(duration) if duration > 0 => return Ok(duration),
+        Ok(_) => debug!("ffprobe found no duration for {}", media_url),
+        Err(e) => debug!("ffprobe failed for {}: {}", media_url, e),
+    }
+
+    let output = Command::new("yt-dlp")
+        .args(["-O", "%(duration)s"])
+        .arg(media_url.as_str())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    let duration: f64 = std::str::from_utf8(&output.stdout)?
+        .trim()
+        .parse()
+        .map_err(|_| {
+            eyre!(
+                "yt-dlp found no duration: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )
+        })?;
+    Ok(duration.round() as usize)
+}
+
+/// points the enclosures of the `podcast:liveItem`s to the live transcode, live streams have
+/// no duration so the transcode is streamed without a length
+fn inject_live_items(channel: &mut Channel, transcode_service_url: Url) -> eyre::Result<()> {
+    let Some(live_items) = channel
+        .extensions
+        .get_mut("podcast")
+        .and_then(|podcast| podcast.get_mut("liveItem"))
+    else {
+        return Ok(());
+    };
+    let bitrate = conf().get(ConfName::Mp3Bitrate)?;
+    let codec: AudioCodec = conf().get(ConfName::AudioCodec)?.into();
+    for live_item in live_items {
+        let Some(link) = live_item
+            .children
+            .get("link")
+            .and_then(|links| links.first())
+            .and_then(|link| link.value())
+            .map(str::to_string)
+        else {
+            continue;
+        };
+        let mut live_transcode_url = transcode_service_url.clone();
+        live_transcode_url
+            .query_pairs_mut()
+            .append_pair("bitrate", bitrate.as_str())
+            .append_pair("uuid", uuid::Uuid::new_v4().to_string().as_str())
+            .append_pair("live", "true")
+            .append_pair("url", link.as_str())
+            .append_pair("ext", format!(".{}", codec.get_extension_str()).as_str());
+        for enclosure in live_item
+            .children
+            .entry("enclosure".to_string())
+            .or_default()
+        {
+            enclosure
+                .attrs
+                .insert("url".to_string(), live_transcode_url.to_string());
+            enclosure
+                .attrs
+                .insert("type".to_string(), codec.get_mime_type_str().to_string());
+        }
+    }
+    Ok(())
+}
+
+/// the time ranges to cut out while transcoding (es: "30-60,600-700"), the extension is internal
+/// to vod2pod so it gets removed from the item
+fn take_cut_ranges(item: &mut Item) -> Option<String> {
+    let cut = item
+        .extensions
+        .remove("vod2pod")?
+        .remove("cut")?
+        .first()?
+        .value()?
+        .to_string();
+    Some(cut)
+}
+
+/// the transcoder writes these in the ID3 tag of the episode
+fn append_id3_metadata(
+    transcode_service_url: &mut Url,
+    item: &Item,
+    channel_title: &str,
+    chapters: &[Chapter],
+) -> eyre::Result<()> {
+    let mut query = transcode_service_url.query_pairs_mut();
+    if let Some(title) = item.title() {
+        query.append_pair("title", title);
+    }
+    if !channel_title.is_empty() {
+        query.append_pair("author", channel_title);
+    }
+    if let Some(date) = item
+        .pub_date()
+        .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+    {
+        query.append_pair("date", date.format("%Y-%m-%d").to_string().as_str());
+    }
+    if !chapters.is_empty() {
+        query.append_pair("chapters", serde_json::to_string(chapters)?.as_str());
+    }
+    Ok(())
+}
+
+/// finds the chapters in a description, es: "01:23 intro" or "1:02:03 - the end"
+fn parse_chapters(description: &str) -> Vec<Chapter> {
+    let re = Regex::new(r"(?m)^\s*(?:(\d{1,2}):)?(\d{1,2}):(\d{2})\s*[-–:]?\s*(.+?)\s*$").unwrap();
+    let chapters: Vec<Chapter> = re
+        .captures_iter(description)
+        .filter_map(|captures| {
+            let hours: usize = captures
+                .get(1)
+                .map_or(Some(0), |h| h.as_str().parse().ok())?;
+            let minutes: usize = captures[2].parse().ok()?;
+            let seconds: usize = captures[3].parse().ok()?;
+            Some(Chapter {
+                start_secs: hours * 3600 + minutes * 60 + seconds,
+                title: captures[4].to_string(),
+            })
+        })
+        .collect();
+
+    // a single timestamp or timestamps out of order are not a list of chapters
+    let is_chapter_list = chapters.len() > 1
+        && chapters
+            .windows(2)
+            .all(|pair| pair[0].start_secs < pair[1].start_secs);
+    if is_chapter_list {
+        chapters
+    } else {
+        Vec::new()
+    }
+}
 
 fn get_description(item: &Item) -> String {
     const FOOTER: &str = concat!(
@@ -128,3 +463,167 @@ fn parse_duration(duration_str: &str) -> Result<Duration, String> {
     Ok(Duration::from_secs(duration_secs))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chapters() {
+        let description = "episode description\n00:00 Intro\n1:30 - First topic\n1:02:03 The end";
+        let chapters = parse_chapters(description);
+        assert_eq!(
+            chapters,
+            vec![
+                Chapter {
+                    start_secs: 0,
+                    title: "Intro".to_string()
+                },
+                Chapter {
+                    start_secs: 90,
+                    title: "First topic".to_string()
+                },
+                Chapter {
+                    start_secs: 3723,
+                    title: "The end".to_string()
+                },
+            ]
+        );
+        assert!(parse_chapters("see you at 10:30 tomorrow").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_enclosure_is_transcoded() {
+        let feed = r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd"><channel><title>podcast</title><link>https://example.com</link><description></description>
+<item><title>episode</title><link>https://example.com/episode-1</link><enclosure url="https://cdn.example.com/episode-1.mp3?source=rss" type="audio/mpeg" length="1000"/><itunes:duration>10:00</itunes:duration></item>
+</channel></rss>"#;
+        let transcode_service_url = Url::parse("http://localhost/transcode_media/to.mp3").unwrap();
+
+        let injected = inject_vod2pod_customizations(feed.to_string(), Some(transcode_service_url))
+            .await
+            .unwrap();
+        let channel = Channel::read_from(injected.as_bytes()).unwrap();
+
+        let item = &channel.items()[0];
+        assert_eq!(item.link(), Some("https://example.com/episode-1"));
+        let enclosure_url = Url::parse(item.enclosure().unwrap().url()).unwrap();
+        let query: Vec<(String, String)> = enclosure_url.query_pairs().into_owned().collect();
+        assert!(query.contains(&(
+            "url".to_string(),
+            "https://cdn.example.com/episode-1.mp3?source=rss".to_string()
+        )));
+        assert!(query.contains(&("duration".to_string(), "600".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_media_url_extension_is_transcoded() {
+        let feed = r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd" xmlns:vod2pod="https://github.com/madiele/vod2pod-rss"><channel><title>channel</title><link>https://www.youtube.com/@channel</link><description></description>
+<item><title>video</title><link>https://www.youtube.com/watch?v=UMO52N2vfk0</link><vod2pod:media_url>https://www.youtube.com/watch?v=UMO52N2vfk0&amp;audio_lang=it</vod2pod:media_url><itunes:duration>10:00</itunes:duration></item>
+</channel></rss>"#;
+        let transcode_service_url = Url::parse("http://localhost/transcode_media/to.mp3").unwrap();
+
+        let injected = inject_vod2pod_customizations(feed.to_string(), Some(transcode_service_url))
+            .await
+            .unwrap();
+        let channel = Channel::read_from(injected.as_bytes()).unwrap();
+
+        let item = &channel.items()[0];
+        assert_eq!(
+            item.link(),
+            Some("https://www.youtube.com/watch?v=UMO52N2vfk0")
+        );
+        assert!(item.extensions().get("vod2pod").is_none());
+        let enclosure_url = Url::parse(item.enclosure().unwrap().url()).unwrap();
+        assert!(enclosure_url.query_pairs().any(|(key, value)| key == "url"
+            && value == "https://www.youtube.com/watch?v=UMO52N2vfk0&audio_lang=it"));
+    }
+
+    #[tokio::test]
+    async fn test_item_without_duration_is_not_transcoded() {
+        // the enclosure points to the internal network, it's never probed
+        let feed = r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0"><channel><title>podcast</title><link>https://example.com</link><description></description>
+<item><title>episode</title><link>https://example.com/episode-1</link><enclosure url="http://127.0.0.1:9/episode-1.mp3" type="audio/mpeg" length="1000"/></item>
+</channel></rss>"#;
+        let transcode_service_url = Url::parse("http://localhost/transcode_media/to.mp3").unwrap();
+
+        let injected = inject_vod2pod_customizations(feed.to_string(), Some(transcode_service_url))
+            .await
+            .unwrap();
+        let channel = Channel::read_from(injected.as_bytes()).unwrap();
+
+        assert_eq!(
+            channel.items()[0].enclosure().unwrap().url(),
+            "http://127.0.0.1:9/episode-1.mp3"
+        );
+    }
+
+    #[test]
+    fn test_only_the_newest_items_are_probed() {
+        let feed = r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd"><channel><title>podcast</title><link>https://example.com</link><description></description>
+<item><title>old</title><pubDate>Mon, 01 May 2023 10:00:00 +0000</pubDate><enclosure url="https://cdn.example.com/old.mp3" type="audio/mpeg" length="1000"/></item>
+<item><title>newest</title><pubDate>Wed, 10 May 2023 10:00:00 +0000</pubDate><enclosure url="https://cdn.example.com/newest.mp3" type="audio/mpeg" length="1000"/></item>
+<item><title>with duration</title><pubDate>Thu, 11 May 2023 10:00:00 +0000</pubDate><enclosure url="https://cdn.example.com/known.mp3" type="audio/mpeg" length="1000"/><itunes:duration>10:00</itunes:duration></item>
+<item><title>new</title><pubDate>Fri, 05 May 2023 10:00:00 +0000</pubDate><enclosure url="https://cdn.example.com/new.mp3" type="audio/mpeg" length="1000"/></item>
+</channel></rss>"#;
+        let channel = Channel::read_from(feed.as_bytes()).unwrap();
+
+        let probed: Vec<usize> = items_to_probe(channel.items(), 2)
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect();
+
+        assert_eq!(probed, vec![1, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_missing_durations_are_probed() {
+        let feed = r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd"><channel><title>podcast</title><link>https://example.com</link><description></description>
+<item><title>found</title><enclosure url="https://cdn.example.com/found.mp3" type="audio/mpeg" length="1000"/></item>
+<item><title>not found</title><enclosure url="https://cdn.example.com/missing.mp3" type="audio/mpeg" length="1000"/></item>
+<item><title>with duration</title><enclosure url="https://cdn.example.com/known.mp3" type="audio/mpeg" length="1000"/><itunes:duration>10:00</itunes:duration></item>
+</channel></rss>"#;
+        let mut channel = Channel::read_from(feed.as_bytes()).unwrap();
+
+        probe_missing_durations(&mut channel.items, |media_url: Url| async move {
+            match media_url.path() {
+                "/found.mp3" => Ok(3723),
+                "/missing.mp3" => Err(eyre!("no duration")),
+                path => panic!("{path} already has a duration"),
+            }
+        })
+        .await;
+
+        let durations: Vec<Option<u64>> = channel.items().iter().map(item_duration).collect();
+        assert_eq!(durations, vec![Some(3723), None, Some(600)]);
+    }
+
+    #[tokio::test]
+    async fn test_inject_live_items() {
+        let feed = r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0" xmlns:podcast="https://podcastindex.org/namespace/1.0"><channel><title>channel</title><link>https://www.twitch.tv/channel</link><description></description>
+<podcast:liveItem status="live" start="2023-05-14T10:00:00Z"><title>live</title><guid>1</guid><link>https://www.twitch.tv/channel</link><enclosure url="https://www.twitch.tv/channel" type="audio/mpeg" length="0"/></podcast:liveItem>
+</channel></rss>"#;
+        let transcode_service_url = Url::parse("http://localhost/transcode_media/to.mp3").unwrap();
+
+        let injected = inject_vod2pod_customizations(feed.to_string(), Some(transcode_service_url))
+            .await
+            .unwrap();
+        let channel = Channel::read_from(injected.as_bytes()).unwrap();
+
+        let live_item = &channel.extensions()["podcast"]["liveItem"][0];
+        let enclosure_url =
+            Url::parse(&live_item.children()["enclosure"][0].attrs()["url"]).unwrap();
+        assert_eq!(enclosure_url.path(), "/transcode_media/to.mp3");
+        let query: Vec<(String, String)> = enclosure_url.query_pairs().into_owned().collect();
+        assert!(query.contains(&("live".to_string(), "true".to_string())));
+        assert!(query.contains(&(
+            "url".to_string(),
+            "https://www.twitch.tv/channel".to_string()
+        )));
+        assert!(!query.iter().any(|(key, _)| key == "duration"));
+    }
+}
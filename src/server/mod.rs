@@ -1,7 +1,8 @@
 use std::{collections::HashMap, net::TcpListener, time::Instant};
 
 use actix_web::{
-    dev::Server, guard, http, middleware, web, App, HttpRequest, HttpResponse, HttpServer,
+    dev::Server, guard, http, middleware, web, web::Bytes, App, HttpRequest, HttpResponse,
+    HttpServer,
 };
 use log::{debug, error, info, warn};
 use regex::Regex;
@@ -9,10 +10,11 @@ use serde::Deserialize;
 use url::Url;
 
 use crate::{
-    configs::{conf, Conf, ConfName},
+    configs::{conf, AudioCodec, Conf, ConfName},
+    listen_later,
     provider::{self, MediaProvider},
-    rss_transcodizer,
-    transcoder::{FfmpegParameters, Transcoder},
+    rss_transcodizer::{self, FeedFormat},
+    transcoder::{self, EpisodeMetadata, FfmpegParameters, Listener, Transcoder},
 };
 
 pub fn spawn_server(listener: TcpListener) -> eyre::Result<Server> {
@@ -41,6 +43,13 @@ pub fn spawn_server(listener: TcpListener) -> eyre::Result<Server> {
                     .route("transcodize_rss", web::get().to(transcodize_rss))
                     .route("transcodize_rss", web::head().to(transcodize_rss))
                     .route("health", web::get().to(health))
+                    .route("listen_later", web::get().to(listen_later_feed))
+                    .route("listen_later", web::post().to(add_to_listen_later))
+                    .route("listen_later", web::delete().to(remove_from_listen_later))
+                    //a GET version of the POST so that it can be used from a bookmarklet
+                    .route("listen_later/add", web::get().to(add_to_listen_later))
+                    .route("admin/transcodes", web::get().to(list_transcodes))
+                    .route("admin/transcodes/{id}", web::delete().to(kill_transcode))
                     .route("/", web::get().to(index))
                     .route("", web::get().to(index)),
             )
@@ -53,6 +62,146 @@ async fn health() -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
+/// the admin api is only enabled when ADMIN_TOKEN is set
+fn is_admin(req: &HttpRequest) -> bool {
+    has_token(req, ConfName::AdminToken)
+}
+
+/// the listen later feed ends up in podcast apps, so it has its own token, only enabled when
+/// LISTEN_LATER_TOKEN is set
+fn can_listen_later(req: &HttpRequest) -> bool {
+    has_token(req, ConfName::ListenLaterToken)
+}
+
+/// the token can be sent as a bearer token or in the `token` query param
+fn has_token(req: &HttpRequest, token_conf: ConfName) -> bool {
+    let Ok(expected_token) = conf().get(token_conf) else {
+        return false;
+    };
+    let bearer_token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let query_token = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.get("token").cloned());
+
+    [bearer_token, query_token]
+        .iter()
+        .flatten()
+        .any(|token| constant_time_eq(token.as_bytes(), expected_token.as_bytes()))
+}
+
+/// compares every byte so that the time taken doesn't tell how much of the token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn list_transcodes(req: HttpRequest) -> HttpResponse {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    HttpResponse::Ok().json(transcoder::active_sessions())
+}
+
+async fn kill_transcode(req: HttpRequest, id: web::Path<String>) -> HttpResponse {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    if transcoder::kill_session(&id) {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct ListenLaterQuery {
+    url: Url,
+}
+
+/// the listen later list is personal, it needs the LISTEN_LATER_TOKEN
+async fn listen_later_feed(req: HttpRequest) -> HttpResponse {
+    if !can_listen_later(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    let should_transcode = match conf().get(ConfName::TranscodingEnabled) {
+        Ok(value) => !value.eq_ignore_ascii_case("false"),
+        Err(_) => true,
+    };
+    let transcode_service_url = req.url_for("transcode_mp3", [""]).unwrap();
+    // the token must not end up in the feed
+    let mut feed_url = req.full_url();
+    feed_url.set_query(None);
+
+    let feed = match listen_later::generate_rss_feed(&feed_url).await {
+        Ok(raw_rss) => {
+            rss_transcodizer::inject_vod2pod_customizations(
+                raw_rss,
+                should_transcode.then_some(transcode_service_url),
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    match feed {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("application/xml")
+            .body(body),
+        Err(e) => {
+            error!("could not generate the listen later feed:\n{e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn add_to_listen_later(
+    req: HttpRequest,
+    query: web::Query<ListenLaterQuery>,
+) -> HttpResponse {
+    if !can_listen_later(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    let provider = provider::from(&query.url);
+    if !provider
+        .domain_whitelist_regexes()
+        .iter()
+        .any(|r| r.is_match(query.url.as_ref()))
+    {
+        error!(
+            "supplied url ({}) not in whitelist (whitelist is needed to prevent SSRF attack)",
+            query.url
+        );
+        return HttpResponse::Forbidden().body("scheme and host not in whitelist");
+    }
+    match listen_later::add(&query.url).await {
+        Ok(()) => HttpResponse::Ok().body(format!("added {} to listen later", query.url)),
+        Err(e) => {
+            error!("could not add {} to listen later:\n{e}", query.url);
+            HttpResponse::Conflict().finish()
+        }
+    }
+}
+
+async fn remove_from_listen_later(
+    req: HttpRequest,
+    query: web::Query<ListenLaterQuery>,
+) -> HttpResponse {
+    if !can_listen_later(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    match listen_later::remove(&query.url).await {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("could not remove {} from listen later:\n{e}", query.url);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
 async fn index(req: HttpRequest) -> HttpResponse {
     if let (Some(user_agent), Some(remote_addr), Some(referer)) = (
         req.headers().get("User-Agent"),
@@ -62,7 +211,7 @@ async fn index(req: HttpRequest) -> HttpResponse {
         info!(
             "serving homepage - User-Agent: {}, Remote Address: {}, Referer: {}",
             user_agent.to_str().unwrap(),
-            remote_addr.to_string(),
+            remote_addr,
             referer.to_str().unwrap()
         );
     }
@@ -89,31 +238,47 @@ async fn transcodize_rss(
     if !should_transcode {
         warn!("transcoding is disabled");
     }
-    let url = if let Some(x) = query.get("url") {
-        x
-    } else {
-        error!("no url provided");
-        return HttpResponse::BadRequest().finish();
+    let group = match feed_group(&req, &query) {
+        Ok(group) => group,
+        Err(e) => {
+            error!("{e}");
+            return HttpResponse::BadRequest().body(e.to_string());
+        }
     };
 
     let transcode_service_url = req.url_for("transcode_mp3", [""]).unwrap();
 
-    let parsed_url = match Url::parse(url) {
-        Ok(x) => x,
-        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    let format = match query
+        .get("format")
+        .map(|format| format.parse::<FeedFormat>())
+    {
+        Some(Ok(format)) => format,
+        Some(Err(e)) => return HttpResponse::BadRequest().body(e.to_string()),
+        None => FeedFormat::Rss,
     };
 
-    let provider = provider::from(&parsed_url);
-
-    if !provider
-        .domain_whitelist_regexes()
-        .iter()
-        .any(|r| r.is_match(parsed_url.as_ref()))
-    {
-        error!("supplied url ({parsed_url}) not in whitelist (whitelist is needed to prevent SSRF attack)");
-        return HttpResponse::Forbidden().body("scheme and host not in whitelist");
+    for url in &group.urls {
+        if !provider::from(url)
+            .domain_whitelist_regexes()
+            .iter()
+            .any(|r| r.is_match(url.as_ref()))
+        {
+            error!("supplied url ({url}) not in whitelist (whitelist is needed to prevent SSRF attack)");
+            return HttpResponse::Forbidden().body("scheme and host not in whitelist");
+        }
     }
 
+    // a single url keeps the cache key used before merged feeds existed
+    let is_merged = group.urls.len() > 1 || group.title.is_some() || group.image.is_some();
+    let cache_key = if is_merged {
+        format!(
+            "merged_feed={}",
+            serde_json::to_string(&group).unwrap_or_default()
+        )
+    } else {
+        group.urls[0].to_string()
+    };
+
     //check cache
     let Ok(mut redis) = crate::get_redis_client().await else {
         error!("could not get redis client");
@@ -121,23 +286,32 @@ async fn transcodize_rss(
     };
 
     let cached_rss: Option<String> = redis::cmd("GET")
-        .arg(&parsed_url.to_string())
+        .arg(&cache_key)
         .query_async(&mut redis)
         .await
         .unwrap_or_default();
 
     if let Some(cached_rss) = cached_rss {
-        info!("serving cached rss feed for {parsed_url}");
-        return HttpResponse::Ok()
-            .content_type("application/xml")
-            .body(cached_rss);
+        info!("serving cached rss feed for {cache_key}");
+        return feed_response(cached_rss, format);
     }
 
     //generate rss feed
-    let raw_rss = match provider.generate_rss_feed(parsed_url.clone()).await {
+    let generated_rss = if is_merged {
+        provider::generate_merged_feed(&group).await
+    } else {
+        let parsed_url = &group.urls[0];
+        let provider = provider::from(parsed_url);
+        if provider.is_episode_url(parsed_url) {
+            provider::generate_episode_feed(&provider, parsed_url).await
+        } else {
+            provider.generate_rss_feed(parsed_url.clone()).await
+        }
+    };
+    let raw_rss = match generated_rss {
         Ok(raw_rss) => raw_rss,
         Err(e) => {
-            error!("could not generate rss feed for {parsed_url}:\n{e}");
+            error!("could not generate rss feed for {cache_key}:\n{e}");
             return HttpResponse::Conflict().finish();
         }
     };
@@ -146,7 +320,8 @@ async fn transcodize_rss(
     let injected_feed = rss_transcodizer::inject_vod2pod_customizations(
         raw_rss,
         should_transcode.then_some(transcode_service_url),
-    );
+    )
+    .await;
 
     let body = match injected_feed {
         Ok(body) => body,
@@ -163,7 +338,7 @@ async fn transcodize_rss(
         Err(_) => 600,
     };
     let _: () = redis::cmd("SET")
-        .arg(&parsed_url.to_string())
+        .arg(&cache_key)
         .arg(&body)
         .arg("EX")
         .arg(cache_ttl)
@@ -175,16 +350,95 @@ async fn transcodize_rss(
     let duration = end_time - start_time;
     debug!("rss generation took {} seconds", duration.as_secs_f32());
 
-    HttpResponse::Ok()
-        .content_type("application/xml")
-        .body(body)
+    feed_response(body, format)
+}
+
+/// the sources of the feed: the `url` params (repeat it to merge more feeds) and the urls of the
+/// `group` saved in FEED_GROUPS, `title` and `image` customize the merged feed
+fn feed_group(
+    req: &HttpRequest,
+    query: &HashMap<String, String>,
+) -> eyre::Result<provider::FeedGroup> {
+    let mut group = match query.get("group") {
+        Some(name) => provider::saved_feed_group(name)?,
+        None => provider::FeedGroup::default(),
+    };
+    for (key, value) in url::form_urlencoded::parse(req.query_string().as_bytes()) {
+        if key == "url" {
+            group.urls.push(Url::parse(&value)?);
+        }
+    }
+    if let Some(title) = query.get("title") {
+        group.title = Some(title.to_string());
+    }
+    if let Some(image) = query.get("image") {
+        group.image = Some(Url::parse(image)?);
+    }
+    if group.urls.is_empty() {
+        return Err(eyre::eyre!("no url provided"));
+    }
+    Ok(group)
+}
+
+/// the feeds are cached as rss, the other formats are rendered on each request
+fn feed_response(rss: String, format: FeedFormat) -> HttpResponse {
+    match rss_transcodizer::render(rss, format) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(format.content_type())
+            .body(body),
+        Err(e) => {
+            error!("could not render the feed as {:?}:\n{e}", format);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct TranscodizeQuery {
     url: Url,
     bitrate: usize,
+    /// not sent for live streams
+    #[serde(default)]
     duration: usize,
+    #[serde(default)]
+    live: bool,
+    /// ranges of the source to cut out, es: "30-60,600-700"
+    cut: Option<String>,
+    title: Option<String>,
+    author: Option<String>,
+    date: Option<String>,
+    /// json list of chapters
+    chapters: Option<String>,
+}
+
+/// the transcoded media is fetched by the server, it must belong to a provider or be in a
+/// generated generic feed
+async fn is_media_url_whitelisted(url: &Url) -> bool {
+    provider::from(url)
+        .domain_whitelist_regexes()
+        .iter()
+        .any(|r| r.is_match(url.as_ref()))
+        || provider::is_media_url_allowed(url).await
+}
+
+/// builds the ID3 tag placed before the transcoded audio, empty if there is nothing to tag. The
+/// tag only depends on the query so that it's identical between range requests of the episode
+fn get_id3_tag(query: &TranscodizeQuery) -> Bytes {
+    let metadata = EpisodeMetadata {
+        title: query.title.clone(),
+        author: query.author.clone(),
+        release_date: query.date.clone(),
+        chapters: query
+            .chapters
+            .as_deref()
+            .and_then(|chapters| serde_json::from_str(chapters).ok())
+            .unwrap_or_default(),
+        duration_secs: query.duration,
+    };
+    if metadata.is_empty() {
+        return Bytes::new();
+    }
+    Bytes::from(transcoder::build_tag(&metadata))
 }
 
 fn parse_range_header(
@@ -229,7 +483,7 @@ async fn transcode_to_mp3(req: HttpRequest, query: web::Query<TranscodizeQuery>)
     let stream_url = &query.url;
     let bitrate = query.bitrate;
     let duration_secs = query.duration;
-    let total_streamable_bytes = (duration_secs * bitrate * 1000) / 8;
+    let audio_bytes = (duration_secs * bitrate * 1000) / 8;
     info!("processing transcode at {bitrate}k for {stream_url}");
 
     if let Ok(value) = conf().get(ConfName::TranscodingEnabled) {
@@ -238,17 +492,29 @@ async fn transcode_to_mp3(req: HttpRequest, query: web::Query<TranscodizeQuery>)
         }
     }
 
-    let provider = provider::from(stream_url);
-
-    if !provider
-        .domain_whitelist_regexes()
-        .iter()
-        .any(|r| r.is_match(stream_url.as_ref()))
-    {
+    if !is_media_url_whitelisted(stream_url).await {
         error!("supplied url ({stream_url}) not in whitelist (whitelist is needed to prevent SSRF attack)");
         return HttpResponse::Forbidden().body("scheme and host not in whitelist");
     }
 
+    let codec: AudioCodec = conf().get(ConfName::AudioCodec).unwrap().into();
+    if query.live {
+        return transcode_live(&req, stream_url, bitrate, codec).await;
+    }
+
+    let cut_ranges = match query.cut.as_deref().map(transcoder::parse_cut_ranges) {
+        Some(Ok(cut_ranges)) => cut_ranges,
+        Some(Err(e)) => return HttpResponse::BadRequest().body(e.to_string()),
+        None => Vec::new(),
+    };
+
+    let id3_tag = if matches!(codec, AudioCodec::MP3) {
+        get_id3_tag(&query)
+    } else {
+        Bytes::new()
+    };
+    let total_streamable_bytes = id3_tag.len() + audio_bytes;
+
     // Range header parsing
     const DEFAULT_CONTENT_RANGE: &str = "0-";
     let content_range_str = match req.headers().get("Range") {
@@ -270,8 +536,12 @@ async fn transcode_to_mp3(req: HttpRequest, query: web::Query<TranscodizeQuery>)
         return HttpResponse::RangeNotSatisfiable().finish();
     }
 
-    let seek_secs =
-        ((start_bytes as f32) / (total_streamable_bytes as f32)) * (duration_secs as f32);
+    // the part of the ID3 tag covered by the range is sent as is, the rest is transcoded audio
+    let header = id3_tag.slice(start_bytes.min(id3_tag.len())..(end_bytes + 1).min(id3_tag.len()));
+    let audio_start_bytes = start_bytes.saturating_sub(id3_tag.len());
+    let expected_audio_bytes = expected_bytes - header.len();
+
+    let seek_secs = ((audio_start_bytes as f32) / (audio_bytes as f32)) * (duration_secs as f32);
     debug!("choosen seek_time: {seek_secs}");
 
     let timeout_in_seconds = conf()
@@ -281,15 +551,16 @@ async fn transcode_to_mp3(req: HttpRequest, query: web::Query<TranscodizeQuery>)
         .unwrap();
     debug!("choosen timeout in seconds: {timeout_in_seconds}");
 
-    let codec = conf().get(ConfName::AudioCodec).unwrap().into();
     let ffmpeg_paramenters = FfmpegParameters {
         seek_time: seek_secs,
         url: stream_url.clone(),
         audio_codec: codec,
         bitrate_kbit: bitrate,
         max_rate_kbit: bitrate * 30,
-        expected_bytes_count: expected_bytes,
-        timeout_in_seconds: timeout_in_seconds,
+        expected_bytes_count: expected_audio_bytes,
+        timeout_in_seconds,
+        live: false,
+        cut_ranges,
     };
     debug!("seconds: {duration_secs}, bitrate: {bitrate}");
 
@@ -306,7 +577,7 @@ async fn transcode_to_mp3(req: HttpRequest, query: web::Query<TranscodizeQuery>)
 
     match Transcoder::new(&ffmpeg_paramenters).await {
         Ok(transcoder) => {
-            let stream = transcoder.get_transcode_stream();
+            let stream = transcoder.get_transcode_stream(listener_from_request(&req), header);
 
             let mut response_builder = if ffmpeg_paramenters.seek_time <= 0.1 {
                 HttpResponse::Ok()
@@ -328,10 +599,72 @@ async fn transcode_to_mp3(req: HttpRequest, query: web::Query<TranscodizeQuery>)
     }
 }
 
+/// live streams have no length, so they are sent chunked and without range support
+async fn transcode_live(
+    req: &HttpRequest,
+    stream_url: &Url,
+    bitrate: usize,
+    codec: AudioCodec,
+) -> HttpResponse {
+    info!("processing live transcode for {stream_url}");
+    if req.method() == http::Method::HEAD {
+        return HttpResponse::Ok()
+            .content_type(codec.get_mime_type_str())
+            .finish();
+    }
+
+    let timeout_in_seconds = conf()
+        .get(ConfName::FfmpegTimeoutSeconds)
+        .unwrap()
+        .parse()
+        .unwrap();
+    let ffmpeg_paramenters = FfmpegParameters {
+        seek_time: 0.0,
+        url: stream_url.clone(),
+        audio_codec: codec,
+        bitrate_kbit: bitrate,
+        max_rate_kbit: bitrate * 30,
+        expected_bytes_count: 0,
+        timeout_in_seconds,
+        live: true,
+        cut_ranges: Vec::new(),
+    };
+
+    match Transcoder::new(&ffmpeg_paramenters).await {
+        Ok(transcoder) => HttpResponse::Ok()
+            .insert_header(("Accept-Ranges", "none"))
+            .content_type(codec.get_mime_type_str())
+            .streaming(transcoder.get_transcode_stream(listener_from_request(req), Bytes::new())),
+        Err(e) => HttpResponse::ServiceUnavailable().body(e.to_string()),
+    }
+}
+
+fn listener_from_request(req: &HttpRequest) -> Listener {
+    Listener {
+        client_ip: req
+            .connection_info()
+            .realip_remote_addr()
+            .map(str::to_string),
+        user_agent: req
+            .headers()
+            .get("User-Agent")
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .map(str::to_string),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
     #[test]
     fn test_get_start_and_end_start_to_end() {
         let content_range_str = "bytes=0-99";
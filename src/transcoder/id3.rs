@@ -0,0 +1,183 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// A chapter of an episode, starting at `start_secs` and lasting until the next one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Chapter {
+    pub start_secs: usize,
+    pub title: String,
+}
+
+/// Metadata of an episode that will be written in the ID3v2 tag of the transcoded MP3
+#[derive(Default, Debug, Clone)]
+pub struct EpisodeMetadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// es: 2023-05-14
+    pub release_date: Option<String>,
+    pub chapters: Vec<Chapter>,
+    pub duration_secs: usize,
+}
+
+impl EpisodeMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.author.is_none()
+            && self.release_date.is_none()
+            && self.chapters.is_empty()
+    }
+}
+
+/// Builds an ID3v2.4 tag with the title (TIT2), author (TPE1), release date (TDRC) and chapters
+/// (CTOC + CHAP) of the episode
+pub fn build_tag(metadata: &EpisodeMetadata) -> Vec<u8> {
+    let mut frames = Vec::new();
+    if let Some(ref title) = metadata.title {
+        frames.extend(text_frame(b"TIT2", title));
+    }
+    if let Some(ref author) = metadata.author {
+        frames.extend(text_frame(b"TPE1", author));
+    }
+    if let Some(ref release_date) = metadata.release_date {
+        frames.extend(text_frame(b"TDRC", release_date));
+    }
+    frames.extend(chapter_frames(&metadata.chapters, metadata.duration_secs));
+
+    let mut tag = Vec::with_capacity(frames.len() + 10);
+    tag.extend(b"ID3");
+    tag.extend([4, 0]); // version 2.4.0
+    tag.push(0); // no flags
+    tag.extend(synchsafe(frames.len()));
+    tag.extend(frames);
+    tag
+}
+
+fn synchsafe(size: usize) -> [u8; 4] {
+    if size >= 1 << 28 {
+        warn!("ID3 frame too big, the tag will be corrupted");
+    }
+    [
+        ((size >> 21) & 0x7f) as u8,
+        ((size >> 14) & 0x7f) as u8,
+        ((size >> 7) & 0x7f) as u8,
+        (size & 0x7f) as u8,
+    ]
+}
+
+fn frame(id: &[u8; 4], content: Vec<u8>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(content.len() + 10);
+    frame.extend(id);
+    frame.extend(synchsafe(content.len()));
+    frame.extend([0, 0]); // no flags
+    frame.extend(content);
+    frame
+}
+
+fn text_frame(id: &[u8; 4], text: &str) -> Vec<u8> {
+    const UTF8: u8 = 3;
+    let mut content = vec![UTF8];
+    content.extend(text.as_bytes());
+    frame(id, content)
+}
+
+fn chapter_frames(chapters: &[Chapter], duration_secs: usize) -> Vec<u8> {
+    if chapters.is_empty() {
+        return Vec::new();
+    }
+    // the table of contents can reference at most 255 chapters, the rest are left out
+    let chapters = &chapters[..chapters.len().min(u8::MAX as usize)];
+    let chapter_ids: Vec<String> = (0..chapters.len()).map(|i| format!("chp{i}")).collect();
+
+    let mut toc = Vec::new();
+    toc.extend(b"toc\0");
+    toc.push(0b11); // top level and ordered
+    toc.push(chapters.len() as u8);
+    for id in &chapter_ids {
+        toc.extend(id.as_bytes());
+        toc.push(0);
+    }
+    let mut frames = frame(b"CTOC", toc);
+
+    for (i, chapter) in chapters.iter().enumerate() {
+        let end_secs = chapters
+            .get(i + 1)
+            .map(|next| next.start_secs)
+            .unwrap_or(duration_secs)
+            .max(chapter.start_secs);
+        let mut content = Vec::new();
+        content.extend(chapter_ids[i].as_bytes());
+        content.push(0);
+        content.extend(millis(chapter.start_secs).to_be_bytes());
+        content.extend(millis(end_secs).to_be_bytes());
+        content.extend(u32::MAX.to_be_bytes()); // byte offsets are not used
+        content.extend(u32::MAX.to_be_bytes());
+        content.extend(text_frame(b"TIT2", &chapter.title));
+        frames.extend(frame(b"CHAP", content));
+    }
+    frames
+}
+
+/// chapter times are in milliseconds, saturating at the biggest u32
+fn millis(secs: usize) -> u32 {
+    u32::try_from(secs.saturating_mul(1000)).unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_size_matches_header() {
+        let metadata = EpisodeMetadata {
+            title: Some("episode".to_string()),
+            author: Some("channel".to_string()),
+            release_date: Some("2023-05-14".to_string()),
+            chapters: vec![
+                Chapter {
+                    start_secs: 0,
+                    title: "Intro".to_string(),
+                },
+                Chapter {
+                    start_secs: 95,
+                    title: "Topic".to_string(),
+                },
+            ],
+            duration_secs: 300,
+        };
+        let tag = build_tag(&metadata);
+
+        assert_eq!(&tag[..5], b"ID3\x04\x00");
+        let size = tag[6..10]
+            .iter()
+            .fold(0usize, |size, byte| (size << 7) | *byte as usize);
+        assert_eq!(size + 10, tag.len());
+        for frame_id in [b"TIT2", b"TPE1", b"TDRC", b"CTOC", b"CHAP"] {
+            assert!(tag.windows(4).any(|window| window == frame_id));
+        }
+    }
+
+    #[test]
+    fn chapters_are_capped_to_the_table_of_contents() {
+        let chapters: Vec<Chapter> = (0..300)
+            .map(|i| Chapter {
+                start_secs: i * 60,
+                title: format!("chapter {i}"),
+            })
+            .collect();
+
+        let frames = chapter_frames(&chapters, 300 * 60);
+
+        let chap_count = frames.windows(4).filter(|window| window == b"CHAP").count();
+        assert_eq!(chap_count, u8::MAX as usize);
+        // CTOC header (10) + "toc\0" + flags, then the entry count
+        assert_eq!(frames[15], u8::MAX);
+        assert_eq!(millis(95), 95_000);
+        assert_eq!(millis(usize::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn synchsafe_size() {
+        assert_eq!(synchsafe(257), [0, 0, 2, 1]);
+        assert_eq!(synchsafe(0x0fffffff), [0x7f, 0x7f, 0x7f, 0x7f]);
+    }
+}
@@ -1,26 +1,26 @@
+mod id3;
+mod session;
+
 use std::error::Error;
-use std::io::Read;
 use std::process::Command;
-use std::process::Stdio;
-use std::thread::sleep;
-use std::time::Duration;
 
 use actix_web::web::Bytes;
 use futures::Future;
 use genawaiter::sync::{Co, Gen};
 use log::info;
-use log::{debug, error, warn};
+use log::{debug, error};
 use reqwest::Url;
 use serde::Serialize;
-use tokio::sync::mpsc::channel;
-use tokio::sync::mpsc::Receiver;
-use tokio::sync::mpsc::Sender;
 
 use crate::configs::AudioCodec;
 use crate::provider;
 use crate::provider::MediaProvider;
 
-#[derive(Serialize)]
+pub use self::id3::{build_tag, Chapter, EpisodeMetadata};
+pub use self::session::{active_sessions, kill_session, Listener, ListenerInfo, TranscodeInfo};
+use self::session::{ResumeSource, SessionKey};
+
+#[derive(Serialize, Clone)]
 pub struct FfmpegParameters {
     pub seek_time: f32,
     pub url: Url,
@@ -29,6 +29,11 @@ pub struct FfmpegParameters {
     pub max_rate_kbit: usize,
     pub expected_bytes_count: usize,
     pub timeout_in_seconds: usize,
+    /// live streams can't be seeked and have no known length
+    pub live: bool,
+    /// (start, end) ranges in seconds of the source that are left out of the transcode,
+    /// `seek_time` refers to the transcode with the ranges already removed
+    pub cut_ranges: Vec<(f32, f32)>,
 }
 
 impl FfmpegParameters {
@@ -38,43 +43,91 @@ impl FfmpegParameters {
 }
 
 pub struct Transcoder {
-    ffmpeg_command: Command,
-    expected_bytes_count: usize,
+    /// None when the transcode is already running, the stream url is only resolved to spawn ffmpeg
+    ffmpeg_command: Option<Command>,
+    parameters: FfmpegParameters,
+    session_key: SessionKey,
+    resume_source: Option<ResumeSource>,
+    /// None for live streams, they are sent until they end
+    expected_bytes_count: Option<usize>,
 }
 
 impl Transcoder {
     pub async fn new(ffmpeg_paramenters: &FfmpegParameters) -> eyre::Result<Self> {
-        let provider = provider::from(&ffmpeg_paramenters.url);
+        // a live stream can't be resumed from where it dropped
+        let resume_source = (!ffmpeg_paramenters.live).then(|| ResumeSource {
+            media_url: ffmpeg_paramenters.url.clone(),
+            parameters: ffmpeg_paramenters.clone(),
+            // only MP3 can be resumed by appending a second ffmpeg output, the other codecs
+            // are wrapped in a container that would break in the middle of the stream
+            can_append: matches!(ffmpeg_paramenters.audio_codec, AudioCodec::MP3),
+        });
 
-        let ffmpeg_command = Self::get_ffmpeg_command(&FfmpegParameters {
-            seek_time: ffmpeg_paramenters.seek_time,
-            url: provider.get_stream_url(&ffmpeg_paramenters.url).await?,
-            audio_codec: ffmpeg_paramenters.audio_codec.to_owned(),
+        let session_key = SessionKey {
+            url: ffmpeg_paramenters.url.to_string(),
             bitrate_kbit: ffmpeg_paramenters.bitrate_kbit,
-            max_rate_kbit: ffmpeg_paramenters.max_rate_kbit,
-            expected_bytes_count: ffmpeg_paramenters.expected_bytes_count,
-            timeout_in_seconds: ffmpeg_paramenters.timeout_in_seconds,
-        });
+            codec: ffmpeg_paramenters.audio_codec.get_ffmpeg_codec_str(),
+            seek_time: ffmpeg_paramenters.seek_time.to_string(),
+            cut_ranges: format!("{:?}", ffmpeg_paramenters.cut_ranges),
+            live: ffmpeg_paramenters.live,
+        };
+
+        // listeners joining a running transcode don't need the stream url (es: a yt-dlp call)
+        let ffmpeg_command = if session::is_joinable(&session_key) {
+            None
+        } else {
+            Some(Self::get_stream_command(ffmpeg_paramenters).await?)
+        };
 
         Ok(Self {
             ffmpeg_command,
-            expected_bytes_count: ffmpeg_paramenters.expected_bytes_count,
+            parameters: ffmpeg_paramenters.clone(),
+            session_key,
+            resume_source,
+            expected_bytes_count: (!ffmpeg_paramenters.live)
+                .then_some(ffmpeg_paramenters.expected_bytes_count),
         })
     }
 
+    /// resolves the stream url of the media through its provider
+    async fn get_stream_command(ffmpeg_paramenters: &FfmpegParameters) -> eyre::Result<Command> {
+        let provider = provider::from(&ffmpeg_paramenters.url);
+        Ok(Self::get_ffmpeg_command(&FfmpegParameters {
+            url: provider.get_stream_url(&ffmpeg_paramenters.url).await?,
+            ..ffmpeg_paramenters.clone()
+        }))
+    }
+
     fn get_ffmpeg_command(ffmpeg_paramenters: &FfmpegParameters) -> Command {
         debug!("generating ffmpeg command");
         let mut command = Command::new("ffmpeg");
         let command_ref = &mut command;
 
+        if !ffmpeg_paramenters.live {
+            let seek_time =
+                source_seek_time(ffmpeg_paramenters.seek_time, &ffmpeg_paramenters.cut_ranges);
+            command_ref.args(["-ss", seek_time.to_string().as_str()]);
+        }
+        command_ref.args([
+            "-protocol_whitelist",
+            "file,http,https,tcp,tls",
+            "-i",
+            ffmpeg_paramenters.url.as_str(),
+        ]);
+        if !ffmpeg_paramenters.cut_ranges.is_empty() {
+            // -copyts keeps the source timestamps so that the filter can match the cut ranges
+            let skipped_ranges = ffmpeg_paramenters
+                .cut_ranges
+                .iter()
+                .map(|(start, end)| format!("between(t,{start},{end})"))
+                .collect::<Vec<_>>()
+                .join("+");
+            command_ref.arg("-copyts").args([
+                "-af",
+                format!("aselect='not({skipped_ranges})',asetpts=N/SR/TB").as_str(),
+            ]);
+        }
         command_ref
-            .args(["-ss", ffmpeg_paramenters.seek_time.to_string().as_str()])
-            .args([
-                "-protocol_whitelist",
-                "file,http,https,tcp,tls",
-                "-i",
-                ffmpeg_paramenters.url.as_str(),
-            ])
             .args([
                 "-acodec",
                 ffmpeg_paramenters.audio_codec.get_ffmpeg_codec_str(),
@@ -83,7 +136,12 @@ impl Transcoder {
                 "-ab",
                 format!("{}k", ffmpeg_paramenters.bitrate_kbit).as_str(),
             ])
-            .args(["-f", ffmpeg_paramenters.audio_codec.get_extension_str()])
+            .args(["-f", ffmpeg_paramenters.audio_codec.get_extension_str()]);
+        if matches!(ffmpeg_paramenters.audio_codec, AudioCodec::MP3) {
+            // the ID3 tag is generated by vod2pod so that its size is known in advance
+            command_ref.args(["-id3v2_version", "0"]);
+        }
+        command_ref
             .args([
                 "-bufsize",
                 (ffmpeg_paramenters.bitrate_kbit * 30).to_string().as_str(),
@@ -98,6 +156,7 @@ impl Transcoder {
             ])
             .args(["-hide_banner"])
             .args(["-loglevel", "error"])
+            .args(["-progress", "pipe:2"])
             .arg("-");
         let args: Vec<String> = command_ref
             .get_args()
@@ -111,158 +170,192 @@ impl Transcoder {
         command
     }
 
+    /// `header` is sent before the transcoded audio, it's used for the ID3 tag
     pub fn get_transcode_stream(
         self,
+        listener: Listener,
+        header: Bytes,
     ) -> Gen<Result<Bytes, impl Error>, (), impl Future<Output = ()>> {
         async fn generetor_coroutine(
-            mut command: Command,
-            expected_bytes_count: usize,
+            transcoder: Transcoder,
+            listener: Listener,
+            header: Bytes,
             co: Co<Result<Bytes, std::io::Error>>,
         ) {
-            let mut child = command
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn()
-                .expect("failed to run commnad");
-
-            let mut err = child.stderr.take().expect("failed to open stderr");
-            let mut out = child.stdout.take().expect("failed to open stdout");
-
-            let channel_size: usize = 10;
-            type ChannelBytes = Result<Bytes, std::io::Error>;
-            let (tx, mut rx): (Sender<ChannelBytes>, Receiver<ChannelBytes>) =
-                channel(channel_size);
+            let Transcoder {
+                ffmpeg_command,
+                parameters,
+                session_key,
+                resume_source,
+                expected_bytes_count,
+            } = transcoder;
+            if !header.is_empty() {
+                co.yield_(Ok(header)).await;
+            }
+            if expected_bytes_count == Some(0) {
+                //the request only covered the header
+                return;
+            }
+            let listener = match ffmpeg_command {
+                Some(command) => {
+                    session::attach_or_spawn(session_key, command, resume_source, listener)
+                }
+                None => match session::join(&session_key, listener.clone()) {
+                    Some(listener) => Ok(listener),
+                    // the running transcode ended in the meantime
+                    None => match Transcoder::get_stream_command(&parameters).await {
+                        Ok(command) => {
+                            session::attach_or_spawn(session_key, command, resume_source, listener)
+                        }
+                        Err(e) => Err(std::io::Error::other(e.to_string())),
+                    },
+                },
+            };
+            let listener = match listener {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("failed to run ffmpeg: {}", e);
+                    co.yield_(Err(e)).await;
+                    return;
+                }
+            };
+            let mut state = listener.session().subscribe();
 
-            let tx_stdout = tx.clone();
-            let tx_stderr = tx;
+            let mut next_chunk = listener.next_chunk();
+            let mut sent_bytes_count: usize = 0;
+            info!("streaming to client");
+            loop {
+                let (chunk, finished, error, dropped) = {
+                    let state = state.borrow_and_update();
+                    (
+                        state.chunk(next_chunk),
+                        state.finished,
+                        state.error.clone(),
+                        next_chunk < state.first_chunk,
+                    )
+                };
 
-            //stderr thread
-            std::thread::spawn(move || loop {
-                let mut buf = String::new();
-                match err.read_to_string(&mut buf) {
-                    Ok(0) => {
-                        debug!("ffmpeg stderr closed");
-                        break;
-                    }
-                    Ok(_) => {
-                        error!("{}", buf);
-                        let _ = tx_stderr.blocking_send(Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            buf,
-                        )));
-                    }
-                    Err(e) => {
-                        error!("failed to read from stderr: {}", e);
-                        let _ = tx_stderr
-                            .blocking_send(Err(std::io::Error::new(std::io::ErrorKind::Other, e)));
-                        break;
+                if let Some(mut chunk) = chunk {
+                    next_chunk += 1;
+                    if let Some(expected_bytes_count) = expected_bytes_count {
+                        if sent_bytes_count + chunk.len() >= expected_bytes_count {
+                            //partial request is fulfilled we only need to send the remaining data
+                            chunk.truncate(expected_bytes_count - sent_bytes_count);
+                            listener.add_sent_bytes(chunk.len());
+                            co.yield_(Ok(chunk)).await;
+                            info!("transcoded everything in partial request");
+                            break;
+                        }
                     }
+                    sent_bytes_count += chunk.len();
+                    listener.add_sent_bytes(chunk.len());
+                    co.yield_(Ok(chunk)).await;
+                    // the client took the chunk, ffmpeg can go on
+                    listener.set_next_chunk(next_chunk);
+                    continue;
                 }
-            });
 
-            //stdout thread
-            std::thread::spawn(move || {
-                const BUFFER_SIZE: usize = 1024;
-                let mut buff: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
-                let mut tries = 0;
-                let mut sent_bytes_count: usize = 0;
-                loop {
-                    match out.read(&mut buff) {
-                        Ok(read_bytes) => {
-                            if sent_bytes_count + read_bytes > expected_bytes_count {
-                                //partial request is fulfilled we only need to send the remaining data
-                                let bytes_remaining = expected_bytes_count - sent_bytes_count;
-                                _ = tx_stdout.blocking_send(Ok(Bytes::copy_from_slice(
-                                    &buff[..bytes_remaining],
-                                )));
-                                info!("transcoded everything in partial request");
-                                _ = child.kill();
-                                _ = child.wait();
-                                break;
-                            }
-
-                            if read_bytes == 0 {
-                                info!("transcoded everything");
-                                //pad end of stream with 00000000 bytes if client expects more data to be sent
-                                const NULL_BUFF: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
-                                debug!(
-                                    "sending {} bytes of padding",
-                                    expected_bytes_count - sent_bytes_count
-                                );
-                                while sent_bytes_count < expected_bytes_count {
-                                    let padding_bytes = expected_bytes_count - sent_bytes_count;
-                                    if padding_bytes >= BUFFER_SIZE {
-                                        _ = tx_stdout
-                                            .blocking_send(Ok(Bytes::copy_from_slice(&NULL_BUFF)));
-                                        sent_bytes_count += BUFFER_SIZE;
-                                    } else {
-                                        _ = tx_stdout.blocking_send(Ok(Bytes::copy_from_slice(
-                                            &NULL_BUFF[..padding_bytes],
-                                        )));
-                                        sent_bytes_count += padding_bytes;
-                                    }
-                                }
-                                _ = child.wait();
-                                break;
-                            }
+                if let Some(error) = error {
+                    co.yield_(Err(std::io::Error::other(error))).await;
+                    break;
+                }
 
-                            let send_res = tx_stdout
-                                .blocking_send(Ok(Bytes::copy_from_slice(&buff[..read_bytes])));
+                if dropped {
+                    // the client fell too far behind the other listeners of the transcode
+                    co.yield_(Err(std::io::Error::other("listener fell too far behind")))
+                        .await;
+                    break;
+                }
 
-                            if let Err(e) = send_res {
-                                debug!("{}", e);
-                                info!("connection to client dropped, stopping transcode");
-                                _ = child.kill();
-                                _ = child.wait();
-                                break;
-                            };
-                            sent_bytes_count += read_bytes;
-                        }
-                        Err(e) => match e.kind() {
-                            std::io::ErrorKind::Interrupted => {
-                                if tries > 10 {
-                                    error!("read was interrupted too many times");
-                                    if let Err(err) = tx_stdout.blocking_send(Err(e)) {
-                                        error!("unexpected error occured:");
-                                        error!("{}", err);
-                                        _ = child.kill();
-                                        _ = child.wait();
-                                        break;
-                                    };
-                                }
-                                warn!("read was interrupted, retrying in 1sec");
-                                sleep(Duration::from_secs(1));
-                                tries += 1;
-                            }
-                            _ => {
-                                if let Err(err) = tx_stdout.blocking_send(Err(e)) {
-                                    error!("unexpected error occured:");
-                                    error!("{}", err);
-                                    _ = child.kill();
-                                    _ = child.wait();
-                                    break;
-                                };
-                            }
-                        },
+                if finished {
+                    let Some(expected_bytes_count) = expected_bytes_count else {
+                        info!("live stream ended");
+                        break;
                     };
+                    //pad end of stream with 00000000 bytes if client expects more data to be sent
+                    const BUFFER_SIZE: usize = 1024;
+                    const NULL_BUFF: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+                    debug!(
+                        "sending {} bytes of padding",
+                        expected_bytes_count - sent_bytes_count
+                    );
+                    while sent_bytes_count < expected_bytes_count {
+                        let padding_bytes =
+                            (expected_bytes_count - sent_bytes_count).min(BUFFER_SIZE);
+                        co.yield_(Ok(Bytes::copy_from_slice(&NULL_BUFF[..padding_bytes])))
+                            .await;
+                        sent_bytes_count += padding_bytes;
+                        listener.add_sent_bytes(padding_bytes);
+                    }
+                    break;
                 }
-            });
 
-            info!("streaming to client");
-            while let Some(x) = rx.recv().await {
-                match x {
-                    Ok(bytes) => co.yield_(Ok(bytes)).await,
-                    Err(e) => {
-                        rx.close();
-                        co.yield_(Err(e)).await
-                    }
+                if state.changed().await.is_err() {
+                    break;
                 }
             }
         }
-        Gen::new(|co| generetor_coroutine(self.ffmpeg_command, self.expected_bytes_count, co))
+        Gen::new(|co| generetor_coroutine(self, listener, header, co))
     }
 }
 
+/// reads the duration in seconds of a media with ffprobe
+pub async fn probe_duration(stream_url: &Url) -> eyre::Result<usize> {
+    debug!("probing duration of {}", stream_url);
+    let output = tokio::process::Command::new("ffprobe")
+        .args(["-v", "error"])
+        .args(["-protocol_whitelist", "file,http,https,tcp,tls"])
+        .args(["-show_entries", "format=duration"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(stream_url.as_str())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    let duration: f64 = std::str::from_utf8(&output.stdout)?
+        .trim()
+        .parse()
+        .map_err(|_| {
+            eyre::eyre!(
+                "ffprobe could not find the duration of {}: {}",
+                stream_url,
+                String::from_utf8_lossy(&output.stderr)
+            )
+        })?;
+    Ok(duration.round() as usize)
+}
+
+/// parses cut ranges in the format "30-60,600-700"
+pub fn parse_cut_ranges(cut_ranges: &str) -> eyre::Result<Vec<(f32, f32)>> {
+    let mut ranges = cut_ranges
+        .split(',')
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            let (start, end) = range
+                .split_once('-')
+                .ok_or_else(|| eyre::eyre!("invalid cut range {range}"))?;
+            let (start, end): (f32, f32) = (start.trim().parse()?, end.trim().parse()?);
+            if !start.is_finite() || !end.is_finite() || start >= end || start < 0.0 {
+                return Err(eyre::eyre!("invalid cut range {range}"));
+            }
+            Ok((start, end))
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+    ranges.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Ok(ranges)
+}
+
+/// converts a time of the transcode to the time of the source, skipping the cut ranges before it
+fn source_seek_time(seek_time: f32, cut_ranges: &[(f32, f32)]) -> f32 {
+    let mut source_time = seek_time;
+    for (start, end) in cut_ranges {
+        if *start > source_time {
+            break;
+        }
+        source_time += end - start;
+    }
+    source_time
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -279,15 +372,18 @@ mod test {
             bitrate_kbit: 3,
             expected_bytes_count: 999,
             timeout_in_seconds: 600,
+            live: false,
+            cut_ranges: vec![],
         };
 
         let transcoder = Transcoder::new(&params).await.unwrap();
-        let ppath = transcoder.ffmpeg_command.get_program();
+        let ffmpeg_command = transcoder.ffmpeg_command.unwrap();
+        let ppath = ffmpeg_command.get_program();
         if let Some(x) = ppath.to_str() {
             info!("{} ", x);
             assert_eq!(x, "ffmpeg");
         }
-        let mut args = transcoder.ffmpeg_command.get_args();
+        let mut args = ffmpeg_command.get_args();
 
         while let Some(arg) = args.next() {
             match arg.to_str() {
@@ -343,9 +439,55 @@ mod test {
                     let value = args.next().unwrap().to_str().unwrap();
                     info!("-loglevel {}", value);
                 }
+                Some("-id3v2_version") => {
+                    let value = args.next().unwrap().to_str().unwrap();
+                    info!("-id3v2_version {}", value);
+                    assert_eq!(value, "0");
+                }
+                Some("-progress") => {
+                    let value = args.next().unwrap().to_str().unwrap();
+                    info!("-progress {}", value);
+                    assert_eq!(value, "pipe:2");
+                }
                 Some(x) => panic!("ffmpeg run with uknown option: {x}"),
                 None => panic!("ffmpeg run with no options"),
             }
         }
     }
+
+    #[test]
+    fn cut_ranges_are_skipped() {
+        let cut_ranges = parse_cut_ranges("600-700,30-60").unwrap();
+        assert_eq!(cut_ranges, vec![(30.0, 60.0), (600.0, 700.0)]);
+        assert!(parse_cut_ranges("60-30").is_err());
+        for invalid in ["NaN-5", "0-NaN", "0-inf", "inf-inf", "-inf-5"] {
+            assert!(parse_cut_ranges(invalid).is_err(), "{invalid}");
+        }
+
+        assert_eq!(source_seek_time(10.0, &cut_ranges), 10.0);
+        assert_eq!(source_seek_time(30.0, &cut_ranges), 60.0);
+        assert_eq!(source_seek_time(560.0, &cut_ranges), 590.0);
+        assert_eq!(source_seek_time(580.0, &cut_ranges), 710.0);
+
+        let command = Transcoder::get_ffmpeg_command(&FfmpegParameters {
+            seek_time: 30.0,
+            url: Url::parse("http://url.mp3").unwrap(),
+            audio_codec: AudioCodec::MP3,
+            bitrate_kbit: 64,
+            max_rate_kbit: 64,
+            expected_bytes_count: 999,
+            timeout_in_seconds: 600,
+            live: false,
+            cut_ranges,
+        });
+        let args: Vec<String> = command
+            .get_args()
+            .map(|x| x.to_string_lossy().to_string())
+            .collect();
+        assert!(args.windows(2).any(|arg| arg == ["-ss", "60"]));
+        assert!(args.contains(&"-copyts".to_string()));
+        assert!(args.contains(
+            &"aselect='not(between(t,30,60)+between(t,600,700))',asetpts=N/SR/TB".to_string()
+        ));
+    }
 }
@@ -0,0 +1,907 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, Weak};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use actix_web::web::Bytes;
+use log::{debug, error, info, warn};
+use reqwest::Url;
+use serde::Serialize;
+use tokio::sync::{oneshot, watch};
+use uuid::Uuid;
+
+use crate::provider::{self, MediaProvider};
+
+use super::{FfmpegParameters, Transcoder};
+
+/// Identifies a transcode, requests with the same key produce the exact same ffmpeg output
+/// so they can share a single ffmpeg process.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct SessionKey {
+    pub url: String,
+    pub bitrate_kbit: usize,
+    pub codec: &'static str,
+    pub seek_time: String,
+    pub cut_ranges: String,
+    pub live: bool,
+}
+
+/// ffmpeg is paused when it's this many chunks (up to 1KiB each) ahead of every listener
+const MAX_CHUNKS_AHEAD: usize = 256;
+/// the start of a transcode is kept until this many chunks were produced so that late joiners
+/// can replay it, after that new listeners get their own ffmpeg process
+const REPLAY_WINDOW_CHUNKS: usize = 2048;
+/// a listener this many chunks behind ffmpeg is dropped, a client that paused the playback
+/// must not hold back the other listeners
+const MAX_CHUNKS_BEHIND: usize = 4096;
+
+#[derive(Default)]
+pub struct SessionState {
+    /// the chunks ffmpeg produced that are still buffered, the oldest are dropped once every
+    /// listener sent them and the start can't be replayed anymore
+    pub chunks: VecDeque<Bytes>,
+    /// index of the first buffered chunk
+    pub first_chunk: usize,
+    pub transcoded_bytes: usize,
+    pub finished: bool,
+    pub error: Option<String>,
+}
+
+impl SessionState {
+    pub fn chunk(&self, index: usize) -> Option<Bytes> {
+        let index = index.checked_sub(self.first_chunk)?;
+        self.chunks.get(index).cloned()
+    }
+
+    /// index of the next chunk that will be produced
+    pub fn end(&self) -> usize {
+        self.first_chunk + self.chunks.len()
+    }
+}
+
+/// Progress reported by ffmpeg through `-progress`
+#[derive(Default, Clone, Copy)]
+struct FfmpegProgress {
+    speed: Option<f32>,
+    out_time_secs: Option<f32>,
+}
+
+/// The client that requested a transcode, only used to show who is listening
+#[derive(Default, Clone)]
+pub struct Listener {
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+struct ListenerStats {
+    listener: Listener,
+    connected_at: Instant,
+    sent_bytes: AtomicUsize,
+    /// index of the next chunk the listener will send
+    next_chunk: AtomicUsize,
+}
+
+/// A running ffmpeg process whose output is broadcasted to every listener attached to it.
+///
+/// The session lives as long as at least one listener holds it, when the last listener drops
+/// it ffmpeg is killed.
+pub struct TranscodeSession {
+    id: Uuid,
+    key: SessionKey,
+    started_at: Instant,
+    state: watch::Sender<SessionState>,
+    progress: Mutex<FfmpegProgress>,
+    listeners: Mutex<HashMap<Uuid, Arc<ListenerStats>>>,
+    /// notified when a listener sends a chunk or leaves, ffmpeg waits on it to not run too far
+    /// ahead of the listeners
+    listener_moved: Condvar,
+    ffmpeg: Mutex<Option<Arc<Mutex<Child>>>>,
+    killed: AtomicBool,
+}
+
+impl TranscodeSession {
+    pub fn subscribe(&self) -> watch::Receiver<SessionState> {
+        self.state.subscribe()
+    }
+
+    /// blocks while every listener is too far behind, the listeners that fell too far behind
+    /// are dropped so that the others can go on. Live streams never block, pausing ffmpeg would
+    /// move every listener away from the live edge
+    fn push_chunk(&self, chunk: Bytes) {
+        let mut listeners = self.listeners.lock().unwrap();
+        loop {
+            let end = self.state.borrow().end();
+            let fastest = fastest_listener(&listeners).unwrap_or(end);
+            if self.key.live
+                || end.saturating_sub(fastest) < MAX_CHUNKS_AHEAD
+                || self.killed.load(Ordering::SeqCst)
+            {
+                break;
+            }
+            listeners = self
+                .listener_moved
+                .wait_timeout(listeners, Duration::from_secs(1))
+                .unwrap()
+                .0;
+        }
+        self.state.send_modify(|state| {
+            state.transcoded_bytes += chunk.len();
+            state.chunks.push_back(chunk);
+            let end = state.end();
+            // their chunks get dropped below, the listener stops when it doesn't find the next one
+            listeners.retain(|_, stats| {
+                let lagging =
+                    end.saturating_sub(stats.next_chunk.load(Ordering::SeqCst)) > MAX_CHUNKS_BEHIND;
+                if lagging {
+                    warn!(
+                        "dropping a listener of {} that is too far behind",
+                        self.key.url
+                    );
+                }
+                !lagging
+            });
+            // live streams are never replayed, they start from the live edge
+            if self.key.live || end > REPLAY_WINDOW_CHUNKS {
+                let slowest = slowest_listener(&listeners).unwrap_or(end);
+                while state.first_chunk < slowest && state.chunks.pop_front().is_some() {
+                    state.first_chunk += 1;
+                }
+            }
+        });
+        drop(listeners);
+    }
+
+    fn finish(&self) {
+        self.state.send_modify(|state| state.finished = true);
+    }
+
+    fn fail(&self, error: String) {
+        self.state.send_modify(|state| state.error = Some(error));
+    }
+
+    fn kill(&self) {
+        info!("killing transcode session {} for {}", self.id, self.key.url);
+        self.killed.store(true, Ordering::SeqCst);
+        self.fail("transcode killed".to_string());
+        self.listener_moved.notify_all();
+        if let Some(ffmpeg) = self.ffmpeg.lock().unwrap().as_ref() {
+            _ = ffmpeg.lock().unwrap().kill();
+        }
+    }
+
+    fn info(&self) -> TranscodeInfo {
+        let progress = *self.progress.lock().unwrap();
+        // push_chunk locks the listeners before the state, the same order is kept here
+        let listeners = self
+            .listeners
+            .lock()
+            .unwrap()
+            .values()
+            .map(|stats| ListenerInfo {
+                client_ip: stats.listener.client_ip.clone(),
+                user_agent: stats.listener.user_agent.clone(),
+                sent_bytes: stats.sent_bytes.load(Ordering::Relaxed),
+                connected_secs: stats.connected_at.elapsed().as_secs(),
+            })
+            .collect();
+        let state = self.state.borrow();
+        TranscodeInfo {
+            id: self.id.to_string(),
+            url: self.key.url.clone(),
+            provider: Url::parse(&self.key.url)
+                .map(|url| provider::from(&url).name())
+                .unwrap_or_default(),
+            codec: self.key.codec,
+            bitrate_kbit: self.key.bitrate_kbit,
+            seek_time: self.key.seek_time.parse().unwrap_or_default(),
+            runtime_secs: self.started_at.elapsed().as_secs(),
+            transcoded_bytes: state.transcoded_bytes,
+            finished: state.finished,
+            speed: progress.speed,
+            out_time_secs: progress.out_time_secs,
+            listeners,
+        }
+    }
+}
+
+/// A listener attached to a session, dropping it detaches the listener
+pub struct ListenerHandle {
+    id: Uuid,
+    stats: Arc<ListenerStats>,
+    session: Arc<TranscodeSession>,
+}
+
+impl ListenerHandle {
+    pub fn session(&self) -> &Arc<TranscodeSession> {
+        &self.session
+    }
+
+    pub fn add_sent_bytes(&self, bytes: usize) {
+        self.stats.sent_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// the first chunk to send, live listeners join at the live edge
+    pub fn next_chunk(&self) -> usize {
+        self.stats.next_chunk.load(Ordering::SeqCst)
+    }
+
+    /// called once the chunks before `next_chunk` were sent, lets ffmpeg continue
+    pub fn set_next_chunk(&self, next_chunk: usize) {
+        let _listeners = self.session.listeners.lock().unwrap();
+        self.stats.next_chunk.store(next_chunk, Ordering::SeqCst);
+        self.session.listener_moved.notify_all();
+    }
+}
+
+impl Drop for ListenerHandle {
+    fn drop(&mut self) {
+        self.session.listeners.lock().unwrap().remove(&self.id);
+        self.session.listener_moved.notify_all();
+    }
+}
+
+fn slowest_listener(listeners: &HashMap<Uuid, Arc<ListenerStats>>) -> Option<usize> {
+    listeners
+        .values()
+        .map(|stats| stats.next_chunk.load(Ordering::SeqCst))
+        .min()
+}
+
+fn fastest_listener(listeners: &HashMap<Uuid, Arc<ListenerStats>>) -> Option<usize> {
+    listeners
+        .values()
+        .map(|stats| stats.next_chunk.load(Ordering::SeqCst))
+        .max()
+}
+
+#[derive(Serialize)]
+pub struct ListenerInfo {
+    pub client_ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub sent_bytes: usize,
+    pub connected_secs: u64,
+}
+
+#[derive(Serialize)]
+pub struct TranscodeInfo {
+    pub id: String,
+    pub url: String,
+    pub provider: &'static str,
+    pub codec: &'static str,
+    pub bitrate_kbit: usize,
+    pub seek_time: f32,
+    pub runtime_secs: u64,
+    pub transcoded_bytes: usize,
+    pub finished: bool,
+    pub speed: Option<f32>,
+    pub out_time_secs: Option<f32>,
+    pub listeners: Vec<ListenerInfo>,
+}
+
+impl Drop for TranscodeSession {
+    fn drop(&mut self) {
+        debug!("dropping transcode session {:?}", self.key);
+        let mut sessions = sessions().lock().unwrap();
+        if sessions
+            .get(&self.key)
+            .is_some_and(|session| session.strong_count() == 0)
+        {
+            sessions.remove(&self.key);
+        }
+    }
+}
+
+fn sessions() -> &'static Mutex<HashMap<SessionKey, Weak<TranscodeSession>>> {
+    static SESSIONS: OnceLock<Mutex<HashMap<SessionKey, Weak<TranscodeSession>>>> = OnceLock::new();
+    SESSIONS.get_or_init(Default::default)
+}
+
+fn running_sessions() -> Vec<Arc<TranscodeSession>> {
+    sessions()
+        .lock()
+        .unwrap()
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+/// Lists all the transcodes currently running
+pub fn active_sessions() -> Vec<TranscodeInfo> {
+    // the sessions are collected first, dropping the last reference to a session while holding
+    // the registry lock would deadlock
+    running_sessions()
+        .iter()
+        .map(|session| session.info())
+        .collect()
+}
+
+/// Kills the transcode with the given id, all its listeners are disconnected
+pub fn kill_session(id: &str) -> bool {
+    match running_sessions()
+        .into_iter()
+        .find(|session| session.id.to_string() == id)
+    {
+        Some(session) => {
+            session.kill();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Everything needed to restart ffmpeg when its input fails.
+pub struct ResumeSource {
+    /// the original media url, used to re-resolve the stream url through its provider
+    pub media_url: Url,
+    /// the parameters of the transcode, the url is replaced by the re-resolved stream url
+    pub parameters: FfmpegParameters,
+    /// if the output of a restarted ffmpeg can be appended to what was already sent
+    pub can_append: bool,
+}
+
+/// Attaches a listener to the running session for `key`, if there is none or its start can't
+/// be replayed anymore `command` is spawned and a new session is registered for it.
+///
+/// If `resume` is set and ffmpeg fails because of its input, the stream url is re-resolved
+/// and the transcode continues from the last emitted timestamp. If the upstream rejects the
+/// stream url before anything was sent (es: an expired googlevideo url) the url is re-resolved
+/// once and the transcode restarted.
+pub fn attach_or_spawn(
+    key: SessionKey,
+    command: Command,
+    resume: Option<ResumeSource>,
+    listener: Listener,
+) -> std::io::Result<ListenerHandle> {
+    // dropping the last reference to a session while holding the registry lock would deadlock,
+    // so a running session that can't be joined is dropped after the lock
+    let mut unjoinable_session = None;
+    let mut sessions = sessions().lock().unwrap();
+
+    if let Some(session) = sessions.get(&key).and_then(Weak::upgrade) {
+        match try_join(session, listener.clone()) {
+            Ok(listener) => return Ok(listener),
+            Err(session) => {
+                info!(
+                    "the start of the running transcode for {} is gone, starting a new one",
+                    key.url
+                );
+                unjoinable_session = Some(session);
+            }
+        }
+    }
+
+    let id = Uuid::new_v4();
+    let stats = listener_stats(listener);
+
+    let session = Arc::new(TranscodeSession {
+        id: Uuid::new_v4(),
+        key: key.clone(),
+        started_at: Instant::now(),
+        state: watch::Sender::new(SessionState::default()),
+        progress: Mutex::default(),
+        listeners: Mutex::default(),
+        listener_moved: Condvar::new(),
+        ffmpeg: Mutex::default(),
+        killed: AtomicBool::new(false),
+    });
+    // the listener must be there before ffmpeg produces anything
+    session
+        .listeners
+        .lock()
+        .unwrap()
+        .insert(id, Arc::clone(&stats));
+    let first_run = match spawn_ffmpeg(command, &session) {
+        Ok(first_run) => first_run,
+        Err(e) => {
+            // the session drop handler needs the lock
+            drop(sessions);
+            return Err(e);
+        }
+    };
+    sessions.insert(key, Arc::downgrade(&session));
+    drop(sessions);
+    drop(unjoinable_session);
+    tokio::spawn(supervise(Arc::downgrade(&session), first_run, resume));
+    Ok(ListenerHandle { id, stats, session })
+}
+
+/// Attaches a listener to the running session for `key`, None if there is no session that
+/// can be joined
+pub fn join(key: &SessionKey, listener: Listener) -> Option<ListenerHandle> {
+    let session = sessions()
+        .lock()
+        .unwrap()
+        .get(key)
+        .and_then(Weak::upgrade)?;
+    try_join(session, listener).ok()
+}
+
+/// true if a listener for `key` would join a running session instead of spawning ffmpeg
+pub fn is_joinable(key: &SessionKey) -> bool {
+    let Some(session) = sessions().lock().unwrap().get(key).and_then(Weak::upgrade) else {
+        return false;
+    };
+    let joinable = can_join(&session, &session.state.borrow());
+    joinable
+}
+
+fn try_join(
+    session: Arc<TranscodeSession>,
+    listener: Listener,
+) -> Result<ListenerHandle, Arc<TranscodeSession>> {
+    // chunks are only dropped while holding the listeners lock
+    let mut listeners = session.listeners.lock().unwrap();
+    let state = session.state.borrow();
+    if !can_join(&session, &state) {
+        drop(state);
+        drop(listeners);
+        return Err(session);
+    }
+    info!("joining already running transcode for {}", session.key.url);
+    let id = Uuid::new_v4();
+    let stats = listener_stats(listener);
+    if session.key.live {
+        stats.next_chunk.store(state.end(), Ordering::SeqCst);
+    }
+    drop(state);
+    listeners.insert(id, Arc::clone(&stats));
+    drop(listeners);
+    Ok(ListenerHandle { id, stats, session })
+}
+
+/// A live session is joined at the live edge, the others only while their start is buffered
+fn can_join(session: &TranscodeSession, state: &SessionState) -> bool {
+    session.key.live || (state.first_chunk == 0 && state.end() <= REPLAY_WINDOW_CHUNKS)
+}
+
+fn listener_stats(listener: Listener) -> Arc<ListenerStats> {
+    Arc::new(ListenerStats {
+        listener,
+        connected_at: Instant::now(),
+        sent_bytes: AtomicUsize::new(0),
+        next_chunk: AtomicUsize::new(0),
+    })
+}
+
+const MAX_RESUME_ATTEMPTS: usize = 5;
+
+/// How a single ffmpeg run ended.
+enum RunOutcome {
+    Finished,
+    Abandoned,
+    Failed { emitted_bytes: usize, error: String },
+}
+
+async fn supervise(
+    session: Weak<TranscodeSession>,
+    mut run: oneshot::Receiver<RunOutcome>,
+    resume: Option<ResumeSource>,
+) {
+    let mut attempts = 0;
+    let mut refreshed_rejected_url = false;
+    let mut emitted_bytes = 0;
+    loop {
+        let outcome = run.await.unwrap_or(RunOutcome::Abandoned);
+        let Some(current_session) = session.upgrade() else {
+            return;
+        };
+        if current_session.killed.load(Ordering::SeqCst) {
+            return;
+        }
+        let (run_bytes, error) = match outcome {
+            RunOutcome::Finished => return current_session.finish(),
+            RunOutcome::Abandoned => return,
+            RunOutcome::Failed {
+                emitted_bytes,
+                error,
+            } => (emitted_bytes, error),
+        };
+        emitted_bytes += run_bytes;
+
+        let Some(ref resume) = resume else {
+            return current_session.fail(error);
+        };
+        if emitted_bytes == 0 && is_upstream_rejection(&error) {
+            if refreshed_rejected_url {
+                error!(
+                    "upstream keeps rejecting the stream of {}",
+                    resume.media_url
+                );
+                return current_session.fail(error);
+            }
+            refreshed_rejected_url = true;
+            warn!(
+                "upstream rejected the stream of {}, refreshing its url",
+                resume.media_url
+            );
+        } else {
+            // a restart that produced nothing means we are at the end or the input is gone for good
+            if !resume.can_append
+                || attempts >= MAX_RESUME_ATTEMPTS
+                || (attempts > 0 && run_bytes == 0)
+            {
+                error!("giving up on resuming transcode for {}", resume.media_url);
+                return current_session.fail(error);
+            }
+            attempts += 1;
+        }
+
+        let bytes_per_second = (resume.parameters.bitrate_kbit * 1000) as f32 / 8.0;
+        let seek_time = resume.parameters.seek_time + emitted_bytes as f32 / bytes_per_second;
+        warn!(
+            "restarting transcode for {} at {seek_time}s (attempt {attempts})",
+            resume.media_url
+        );
+
+        let provider = provider::from(&resume.media_url);
+        let stream_url = match provider.refresh_stream_url(&resume.media_url).await {
+            Ok(stream_url) => stream_url,
+            Err(e) => {
+                error!("could not re-resolve stream url: {e}");
+                return current_session.fail(error);
+            }
+        };
+
+        let command = Transcoder::get_ffmpeg_command(&FfmpegParameters {
+            seek_time,
+            url: stream_url,
+            ..resume.parameters.clone()
+        });
+        run = match spawn_ffmpeg(command, &current_session) {
+            Ok(run) => run,
+            Err(e) => return current_session.fail(e.to_string()),
+        };
+    }
+}
+
+/// ffmpeg only logs the status for the most common http errors, 410 Gone ends up in the
+/// generic 4XX message
+fn is_upstream_rejection(error: &str) -> bool {
+    error.contains("403 Forbidden") || error.contains("4XX Client Error")
+}
+
+fn spawn_ffmpeg(
+    mut command: Command,
+    session: &Arc<TranscodeSession>,
+) -> std::io::Result<oneshot::Receiver<RunOutcome>> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let err = child.stderr.take().expect("failed to open stderr");
+    let mut out = child.stdout.take().expect("failed to open stdout");
+    let child = Arc::new(Mutex::new(child));
+    *session.ffmpeg.lock().unwrap() = Some(Arc::clone(&child));
+    let (outcome_tx, outcome_rx) = oneshot::channel();
+
+    //stderr thread, ffmpeg writes both its progress and its errors here
+    let stderr_session = Arc::downgrade(session);
+    let stderr_thread = std::thread::spawn(move || {
+        let mut errors = String::new();
+        for line in BufReader::new(err).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("failed to read from stderr: {}", e);
+                    errors.push_str(&e.to_string());
+                    break;
+                }
+            };
+            let Some(session) = stderr_session.upgrade() else {
+                continue;
+            };
+            if !update_progress(&mut session.progress.lock().unwrap(), &line) {
+                errors.push_str(&line);
+                errors.push('\n');
+            }
+        }
+        debug!("ffmpeg stderr closed");
+        errors
+    });
+
+    //stdout thread
+    let session = Arc::downgrade(session);
+    std::thread::spawn(move || {
+        const BUFFER_SIZE: usize = 1024;
+        let mut buff: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+        let mut tries = 0;
+        let mut emitted_bytes = 0;
+        let mut read_error = None;
+        loop {
+            let read_result = out.read(&mut buff);
+            let Some(session) = session.upgrade() else {
+                info!("all listeners dropped, stopping transcode");
+                let mut child = child.lock().unwrap();
+                _ = child.kill();
+                _ = child.wait();
+                _ = outcome_tx.send(RunOutcome::Abandoned);
+                return;
+            };
+            match read_result {
+                Ok(0) => break,
+                Ok(read_bytes) => {
+                    session.push_chunk(Bytes::copy_from_slice(&buff[..read_bytes]));
+                    emitted_bytes += read_bytes;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted && tries <= 10 => {
+                    warn!("read was interrupted, retrying in 1sec");
+                    sleep(Duration::from_secs(1));
+                    tries += 1;
+                }
+                Err(e) => {
+                    error!("failed to read from ffmpeg stdout: {}", e);
+                    read_error = Some(e.to_string());
+                    _ = child.lock().unwrap().kill();
+                    break;
+                }
+            }
+        }
+
+        let status = child.lock().unwrap().wait();
+        let stderr = stderr_thread.join().unwrap_or_default();
+        let outcome = match (read_error, status) {
+            (Some(error), _) => RunOutcome::Failed {
+                emitted_bytes,
+                error,
+            },
+            (None, Ok(status)) if status.success() && stderr.trim().is_empty() => {
+                info!("transcoded everything");
+                RunOutcome::Finished
+            }
+            (None, status) => {
+                error!("ffmpeg exited with {:?}: {}", status, stderr);
+                RunOutcome::Failed {
+                    emitted_bytes,
+                    error: stderr,
+                }
+            }
+        };
+        _ = outcome_tx.send(outcome);
+    });
+
+    Ok(outcome_rx)
+}
+
+/// Parses a `key=value` line written by ffmpeg `-progress`, returns false if the line is not
+/// part of the progress report
+fn update_progress(progress: &mut FfmpegProgress, line: &str) -> bool {
+    const PROGRESS_KEYS: [&str; 11] = [
+        "frame",
+        "fps",
+        "bitrate",
+        "total_size",
+        "out_time_us",
+        "out_time_ms",
+        "out_time",
+        "dup_frames",
+        "drop_frames",
+        "speed",
+        "progress",
+    ];
+    let Some((key, value)) = line.trim().split_once('=') else {
+        return false;
+    };
+    match key {
+        "speed" => progress.speed = value.trim().trim_end_matches('x').parse().ok(),
+        // despite the name out_time_ms is in microseconds too
+        "out_time_us" => {
+            progress.out_time_secs = value.parse::<f32>().ok().map(|us| us / 1_000_000.0)
+        }
+        key if PROGRESS_KEYS.contains(&key) || key.starts_with("stream_") => {}
+        _ => return false,
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sleep_command() -> Command {
+        let mut command = Command::new("sleep");
+        command.arg("1");
+        command
+    }
+
+    fn key(seek_time: &str) -> SessionKey {
+        SessionKey {
+            url: "http://url.mp3".to_string(),
+            bitrate_kbit: 64,
+            codec: "libmp3lame",
+            seek_time: seek_time.to_string(),
+            cut_ranges: String::new(),
+            live: false,
+        }
+    }
+
+    fn zeroes_command(bytes: usize) -> Command {
+        let mut command = Command::new("head");
+        command.args(["-c", &bytes.to_string(), "/dev/zero"]);
+        command
+    }
+
+    #[tokio::test]
+    async fn same_key_shares_session() {
+        let first = attach_or_spawn(key("0"), sleep_command(), None, Listener::default()).unwrap();
+        let second = attach_or_spawn(key("0"), sleep_command(), None, Listener::default()).unwrap();
+        let other = attach_or_spawn(key("10"), sleep_command(), None, Listener::default()).unwrap();
+
+        assert!(Arc::ptr_eq(first.session(), second.session()));
+        assert!(!Arc::ptr_eq(first.session(), other.session()));
+        assert_eq!(first.session().info().listeners.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn running_session_is_joined_without_a_command() {
+        assert!(!is_joinable(&key("60")));
+        assert!(join(&key("60"), Listener::default()).is_none());
+
+        let first = attach_or_spawn(key("60"), sleep_command(), None, Listener::default()).unwrap();
+        assert!(is_joinable(&key("60")));
+        let second = join(&key("60"), Listener::default()).unwrap();
+        assert!(Arc::ptr_eq(first.session(), second.session()));
+    }
+
+    #[test]
+    fn detects_upstream_rejections() {
+        assert!(is_upstream_rejection(
+            "[https @ 0x5581] HTTP error 403 Forbidden\nServer returned 403 Forbidden (access denied)"
+        ));
+        assert!(is_upstream_rejection(
+            "Server returned 4XX Client Error, but not one of 40{0,1,3,4}"
+        ));
+        assert!(!is_upstream_rejection(
+            "Error in the pull function.\nIO error: Connection reset by peer"
+        ));
+    }
+
+    #[test]
+    fn parses_ffmpeg_progress() {
+        let mut progress = FfmpegProgress::default();
+
+        assert!(update_progress(&mut progress, "out_time_us=12500000"));
+        assert!(update_progress(&mut progress, "speed=1.52x"));
+        assert!(update_progress(&mut progress, "stream_0_0_q=-0.0"));
+        assert!(!update_progress(
+            &mut progress,
+            "Error in the pull function."
+        ));
+        assert_eq!(progress.out_time_secs, Some(12.5));
+        assert_eq!(progress.speed, Some(1.52));
+
+        assert!(update_progress(&mut progress, "speed=N/A"));
+        assert_eq!(progress.speed, None);
+    }
+
+    #[tokio::test]
+    async fn killed_session_reports_error() {
+        let listener =
+            attach_or_spawn(key("30"), sleep_command(), None, Listener::default()).unwrap();
+        let id = listener.session().id.to_string();
+
+        assert!(active_sessions().iter().any(|session| session.id == id));
+        assert!(kill_session(&id));
+        assert!(listener.session().subscribe().borrow().error.is_some());
+        assert!(!kill_session(&Uuid::new_v4().to_string()));
+    }
+
+    #[tokio::test]
+    async fn failed_run_without_resume_reports_error() {
+        let mut command = Command::new("sh");
+        command.args(["-c", "printf data; echo boom >&2; exit 1"]);
+        let listener = attach_or_spawn(key("20"), command, None, Listener::default()).unwrap();
+
+        let mut state = listener.session().subscribe();
+        let state = state
+            .wait_for(|state| state.error.is_some() || state.finished)
+            .await
+            .unwrap();
+        assert_eq!(
+            state.chunks.iter().flatten().copied().collect::<Vec<u8>>(),
+            b"data"
+        );
+        assert_eq!(state.error.as_deref().map(str::trim), Some("boom"));
+    }
+
+    #[tokio::test]
+    async fn ffmpeg_waits_for_the_listeners() {
+        let listener = attach_or_spawn(
+            key("40"),
+            zeroes_command(1 << 20),
+            None,
+            Listener::default(),
+        )
+        .unwrap();
+        let mut state = listener.session().subscribe();
+
+        state
+            .wait_for(|state| state.end() >= MAX_CHUNKS_AHEAD)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(state.borrow().end(), MAX_CHUNKS_AHEAD);
+
+        listener.set_next_chunk(MAX_CHUNKS_AHEAD);
+        state
+            .wait_for(|state| state.end() > MAX_CHUNKS_AHEAD)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn lagging_listener_is_dropped() {
+        let first = attach_or_spawn(
+            key("45"),
+            zeroes_command(8 << 20),
+            None,
+            Listener::default(),
+        )
+        .unwrap();
+        let paused =
+            attach_or_spawn(key("45"), zeroes_command(1), None, Listener::default()).unwrap();
+        assert!(Arc::ptr_eq(first.session(), paused.session()));
+
+        let mut state = first.session().subscribe();
+        while state.borrow_and_update().first_chunk == 0 {
+            let end = state.borrow().end();
+            first.set_next_chunk(end);
+            _ = tokio::time::timeout(Duration::from_millis(100), state.changed()).await;
+        }
+        assert!(state.borrow().end() > MAX_CHUNKS_BEHIND);
+        assert!(state.borrow().first_chunk > paused.next_chunk());
+        assert_eq!(first.session().info().listeners.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn late_joiner_outside_the_replay_window_gets_a_new_session() {
+        let first = attach_or_spawn(
+            key("50"),
+            zeroes_command(3 << 20),
+            None,
+            Listener::default(),
+        )
+        .unwrap();
+        let mut state = first.session().subscribe();
+        while state.borrow_and_update().first_chunk == 0 {
+            let end = state.borrow().end();
+            first.set_next_chunk(end);
+            _ = tokio::time::timeout(Duration::from_millis(100), state.changed()).await;
+        }
+        assert!(state.borrow().chunks.len() <= MAX_CHUNKS_AHEAD + 1);
+
+        let second =
+            attach_or_spawn(key("50"), zeroes_command(1), None, Listener::default()).unwrap();
+        assert!(!Arc::ptr_eq(first.session(), second.session()));
+        assert_eq!(second.next_chunk(), 0);
+    }
+
+    #[tokio::test]
+    async fn live_listeners_join_at_the_live_edge() {
+        let live_key = SessionKey {
+            live: true,
+            ..key("0")
+        };
+        let first = attach_or_spawn(
+            live_key.clone(),
+            zeroes_command(6 << 20),
+            None,
+            Listener::default(),
+        )
+        .unwrap();
+        let mut state = first.session().subscribe();
+        // nobody reads the stream, ffmpeg goes on anyway and the first listener is dropped
+        let end = state.wait_for(|state| state.finished).await.unwrap().end();
+        assert_eq!(state.borrow().transcoded_bytes, 6 << 20);
+        assert!(state.borrow().first_chunk > first.next_chunk());
+        assert!(first.session().info().listeners.is_empty());
+
+        let second =
+            attach_or_spawn(live_key, zeroes_command(1), None, Listener::default()).unwrap();
+        assert!(Arc::ptr_eq(first.session(), second.session()));
+        assert_eq!(second.next_chunk(), end);
+    }
+}